@@ -0,0 +1,290 @@
+use rulinalg::vector::Vector;
+use rulinalg::matrix::{Matrix, BaseMatrix};
+
+use linearkalman::{KalmanFilter as KF, update_step};
+use linearkalman::KalmanState as KS;
+
+use opencv::core::Scalar;
+
+use rust_drone_follow::traits::Filter;
+use rust_drone_follow::models::GeometricPoint;
+use rust_drone_follow::utils::MarkerDrawer;
+use rust_drone_follow::utils::opencv_custom::get_blue;
+
+use crate::kalman_filter::{KalmanSettings, KalmanError, FrameClock, FrameTimestamp};
+use crate::kalman_filter::{covariance_matrix, mahalanobis_distance, normalize_angle, position_certainty};
+
+/// Below this yaw rate the hat is taken to move in a straight line (to avoid dividing by zero).
+const MIN_YAW_RATE: f64 = 1e-4;
+
+/// Extended Kalman filter with a constant turn rate and velocity (CTRV) motion model.
+/// The state is (x, y, heading, speed, yaw rate), where the heading is the measured angle of the hat,
+/// so people walking in curves are followed without lagging behind.
+pub struct CtrvFilter {
+    filter: KF,
+    position_filter: KF,
+    state: KS,
+    point: Option<GeometricPoint>,
+    sigma_gain: f64,
+    est_v_loss: f64,
+    base_q: Matrix<f64>,
+    measurement_variance: f64,
+    missed_frames: usize,
+    max_coast_frames: usize,
+    clock: FrameClock,
+    gate_threshold: f64,
+    rejected_count: usize,
+}
+
+impl CtrvFilter {
+    /// Creates a new CtrvFilter from the same settings as the KalmanFilter. The noise matrices are
+    /// interpreted for the (x, y, heading, speed, yaw rate) state, est_v_loss is applied to the speed.
+    pub fn from_settings(settings: &KalmanSettings) -> Result<CtrvFilter, KalmanError> {
+        let q = covariance_matrix("Q", 5, &settings.q)?;
+        let r = covariance_matrix("R", 3, &settings.r)?;
+        let p = covariance_matrix("P0", 5, &[settings.sigma0; 5])?;
+
+        Ok(CtrvFilter {
+            filter: KF {
+                q: q.clone(),
+                r: r.clone(),
+                h: Matrix::new(3, 5, vec![1.0, 0.0, 0.0, 0.0, 0.0,
+                                          0.0, 1.0, 0.0, 0.0, 0.0,
+                                          0.0, 0.0, 1.0, 0.0, 0.0]),
+                // The transition is non-linear, it is computed in predict
+                f: Matrix::identity(5),
+                x0: Vector::zeros(5),
+                p0: Matrix::identity(5),
+            },
+            position_filter: KF {
+                q: q.clone(),
+                r: r.select(&[0, 1], &[0, 1]),
+                h: Matrix::new(2, 5, vec![1.0, 0.0, 0.0, 0.0, 0.0,
+                                          0.0, 1.0, 0.0, 0.0, 0.0]),
+                f: Matrix::identity(5),
+                x0: Vector::zeros(5),
+                p0: Matrix::identity(5),
+            },
+            state: KS {
+                x: Vector::zeros(5),
+                p,
+            },
+            point: None,
+            sigma_gain: settings.sigma_gain,
+            est_v_loss: settings.est_v_loss,
+            base_q: q,
+            measurement_variance: (r[[0, 0]] + r[[1, 1]]) / 2.0,
+            missed_frames: 0,
+            max_coast_frames: settings.max_coast_frames,
+            clock: FrameClock::new(settings.frame_time),
+            gate_threshold: settings.gate_threshold,
+            rejected_count: 0,
+        })
+    }
+
+    /// Measures the time between the updates with the timestamps of the frames, see FrameTimestamp.
+    pub fn with_frame_timestamps(mut self, timestamps: FrameTimestamp) -> CtrvFilter {
+        self.clock.set_timestamps(timestamps);
+        self
+    }
+
+    /// Moves the state dt frames forward along the turn, and propagates the covariance
+    /// with the Jacobian of the motion model.
+    fn predict(&self, state: &KS, dt: f64) -> KS {
+        let (x, y, theta, v, omega) = (state.x[0], state.x[1], state.x[2], state.x[3], state.x[4]);
+        let theta1 = theta + omega * dt;
+        let v_loss = self.est_v_loss.powf(dt);
+
+        let mut j = Matrix::identity(5);
+        let (new_x, new_y) = if omega.abs() > MIN_YAW_RATE {
+            let (sin_diff, cos_diff) = (theta1.sin() - theta.sin(), theta.cos() - theta1.cos());
+            j[[0, 2]] = v / omega * (theta1.cos() - theta.cos());
+            j[[0, 3]] = sin_diff / omega;
+            j[[0, 4]] = v * dt * theta1.cos() / omega - v * sin_diff / (omega * omega);
+            j[[1, 2]] = v / omega * sin_diff;
+            j[[1, 3]] = cos_diff / omega;
+            j[[1, 4]] = v * dt * theta1.sin() / omega - v * cos_diff / (omega * omega);
+            (x + v / omega * sin_diff, y + v / omega * cos_diff)
+        } else {
+            j[[0, 2]] = -v * dt * theta.sin();
+            j[[0, 3]] = dt * theta.cos();
+            j[[0, 4]] = -0.5 * v * dt * dt * theta.sin();
+            j[[1, 2]] = v * dt * theta.cos();
+            j[[1, 3]] = dt * theta.sin();
+            j[[1, 4]] = 0.5 * v * dt * dt * theta.cos();
+            (x + v * dt * theta.cos(), y + v * dt * theta.sin())
+        };
+        j[[2, 4]] = dt;
+        j[[3, 3]] = v_loss;
+
+        KS {
+            x: vector![new_x, new_y, normalize_angle(theta1), v * v_loss, omega],
+            p: &j * &state.p * j.transpose() + &self.base_q * dt,
+        }
+    }
+
+    /// Number of measurements that were rejected by the gate since the filter was created.
+    pub fn get_rejected_count(&self) -> usize {
+        self.rejected_count
+    }
+
+    /// Updates the estimation with a measurement taken dt frames after the previous one.
+    pub fn update_estimation_dt(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, dt: f64) {
+        let pred = self.predict(&self.state, dt);
+        match point {
+            Some(p) => {
+                let measurement = match angle {
+                    Some(a) => vector![p.x as f64, p.y as f64, pred.x[2] + normalize_angle(a - pred.x[2])],
+                    None => vector![p.x as f64, p.y as f64],
+                };
+                let filter = if angle.is_some() { &self.filter } else { &self.position_filter };
+
+                if self.point.is_some() && self.gate_threshold > 0.0 {
+                    if let Some(distance) = mahalanobis_distance(filter, &pred, &measurement) {
+                        if distance > self.gate_threshold {
+                            self.rejected_count += 1;
+                            self.coast(pred, dt);
+                            return;
+                        }
+                    }
+                }
+
+                // The observation model is linear, so the update is the same as for the KalmanFilter
+                let mut next = update_step(filter, &pred, &measurement);
+                next.x[2] = normalize_angle(next.x[2]);
+
+                self.point = Some(GeometricPoint::new(next.x[0] as i32, next.x[1] as i32));
+                self.state = next;
+                self.missed_frames = 0;
+            }
+            None => {
+                self.coast(pred, dt);
+            }
+        }
+    }
+
+    fn coast(&mut self, mut pred: KS, dt: f64) {
+        let gain = self.sigma_gain.powf(dt);
+        if self.point.is_none() {
+            self.state.p = &self.state.p * gain;
+            return;
+        }
+        pred.p = &pred.p * gain;
+        self.state = pred;
+        self.missed_frames += 1;
+
+        self.point = if self.missed_frames > self.max_coast_frames {
+            None
+        } else {
+            Some(GeometricPoint::new(self.state.x[0] as i32, self.state.x[1] as i32))
+        };
+    }
+}
+
+impl Filter for CtrvFilter {
+    fn update_estimation(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, _cert: f64) {
+        let dt = self.clock.elapsed_frames();
+        self.update_estimation_dt(point, angle, dt);
+    }
+
+    fn get_estimated_position(&self) -> Option<GeometricPoint> {
+        self.point.as_ref().map(|p| p.clone())
+    }
+
+    fn get_estimated_angle(&self) -> f64 {
+        self.state.x[2]
+    }
+
+    fn get_estimated_vx(&self) -> f64 {
+        self.state.x[3] * self.state.x[2].cos()
+    }
+
+    fn get_estimated_vy(&self) -> f64 {
+        self.state.x[3] * self.state.x[2].sin()
+    }
+
+    fn get_estimation_certainty(&self) -> f64 {
+        if self.point.is_none() {
+            return 0.0;
+        }
+        position_certainty(&self.state.p, self.measurement_variance)
+    }
+
+    fn draw_on_image(&self, m_d: &mut MarkerDrawer) {
+        if let Some(p) = &self.point {
+            m_d.point(p, get_blue());
+
+            // The path predicted for the next 10 frames
+            let mut state = KS { x: self.state.x.clone(), p: self.state.p.clone() };
+            let mut last = p.clone();
+            for _i in 0..10 {
+                state = self.predict(&state, 1.0);
+                let next = GeometricPoint::new(state.x[0] as i32, state.x[1] as i32);
+                m_d.line(&last, &next, Scalar::new(255.0, 255.0, 255.0, 255.0));
+                last = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::kalman_filter::KalmanFilter;
+    use crate::simulation::traits::MoveTactic;
+    use crate::simulation::movetactics::stand_turn::StandTurn;
+    use crate::simulation::movetactics::move_squares::MoveSquares;
+
+    /// Follows a virtual person with the given filter using noisy detections, and returns
+    /// the RMS error of the estimated position and angle.
+    fn simulated_filter_error<M: MoveTactic, F: Filter>(mut tactic: M, mut filter: F, frames: usize) -> (f64, f64) {
+        let mut rng = StdRng::seed_from_u64(42);
+        let (mut x, mut y, mut a) = (0.0, 0.0, 0.0);
+        let (mut position_error, mut angle_error) = (0.0, 0.0);
+
+        for _i in 0..frames {
+            let (nx, ny, na) = tactic.execute_move(x, y, a);
+            x = nx;
+            y = ny;
+            a = na;
+            let detected = GeometricPoint::new(
+                (x + rng.gen_range(-3.0, 3.0)) as i32,
+                (y + rng.gen_range(-3.0, 3.0)) as i32
+            );
+            filter.update_estimation(Some(detected), Some(normalize_angle(a + rng.gen_range(-0.05, 0.05))), 1.0);
+
+            if let Some(e) = filter.get_estimated_position() {
+                position_error += (e.x as f64 - x).powi(2) + (e.y as f64 - y).powi(2);
+            }
+            angle_error += normalize_angle(filter.get_estimated_angle() - a).powi(2);
+        }
+
+        ((position_error / frames as f64).sqrt(), (angle_error / frames as f64).sqrt())
+    }
+
+    /// Every update is one frame, the simulation runs faster than real time.
+    fn settings() -> KalmanSettings {
+        KalmanSettings { frame_time: 0.0, ..KalmanSettings::default() }
+    }
+
+    /// On people walking in squares the CtrvFilter has to follow the position at least as well as the KalmanFilter.
+    #[test]
+    fn move_squares_comparison_test() {
+        let kalman = simulated_filter_error(MoveSquares::new(2.0, 50), KalmanFilter::from_settings(&settings()).unwrap(), 1000);
+        let ctrv = simulated_filter_error(MoveSquares::new(2.0, 50), CtrvFilter::from_settings(&settings()).unwrap(), 1000);
+        assert!(ctrv.0.is_finite() && ctrv.1.is_finite(), "The CtrvFilter diverged on MoveSquares!");
+        assert!(ctrv.0 <= kalman.0 * 1.2, "The CtrvFilter followed the position worse: {:.2} px, KalmanFilter {:.2} px", ctrv.0, kalman.0);
+    }
+
+    /// On people turning in place the yaw rate of the CtrvFilter has to keep the angle closer than the KalmanFilter,
+    /// which lags behind the turning.
+    #[test]
+    fn stand_turn_comparison_test() {
+        let kalman = simulated_filter_error(StandTurn::new(0.05), KalmanFilter::from_settings(&settings()).unwrap(), 1000);
+        let ctrv = simulated_filter_error(StandTurn::new(0.05), CtrvFilter::from_settings(&settings()).unwrap(), 1000);
+        assert!(ctrv.0.is_finite() && ctrv.1.is_finite(), "The CtrvFilter diverged on StandTurn!");
+        assert!(ctrv.1 < kalman.1, "The CtrvFilter followed the angle worse: {:.3} rad, KalmanFilter {:.3} rad", ctrv.1, kalman.1);
+    }
+}
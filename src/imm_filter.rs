@@ -0,0 +1,339 @@
+use std::f64::consts::PI;
+
+use rulinalg::vector::Vector;
+use rulinalg::matrix::{Matrix, BaseMatrix};
+
+use linearkalman::{KalmanFilter as KF, predict_step, update_step};
+use linearkalman::KalmanState as KS;
+
+use opencv::core::Scalar;
+
+use rust_drone_follow::traits::Filter;
+use rust_drone_follow::models::GeometricPoint;
+use rust_drone_follow::utils::MarkerDrawer;
+use rust_drone_follow::utils::opencv_custom::{get_blue, get_green, get_red};
+
+use crate::kalman_filter::{KalmanSettings, KalmanError, FrameClock, FrameTimestamp};
+use crate::kalman_filter::{covariance_matrix, mahalanobis_distance, normalize_angle, position_certainty};
+
+/// Index of the models in the probability vector
+const STATIONARY: usize = 0;
+const MOVING: usize = 1;
+
+/// Interacting Multiple Model filter mixing a stationary and a constant velocity Kalman filter
+/// over the (x, y, angle, vx, vy) state, for people who keep stopping and starting again.
+pub struct ImmFilter {
+    /// The stationary and the constant velocity model (in this order)
+    models: [KF; 2],
+    position_models: [KF; 2],
+    states: [KS; 2],
+    probabilities: [f64; 2],
+    /// switching[i][j] is the probability of going from model i to model j in one frame
+    switching: [[f64; 2]; 2],
+    base_q: Matrix<f64>,
+    est_v_loss: f64,
+    sigma_gain: f64,
+    measurement_variance: f64,
+    state: KS,
+    point: Option<GeometricPoint>,
+    missed_frames: usize,
+    max_coast_frames: usize,
+    clock: FrameClock,
+}
+
+fn model(q: &Matrix<f64>, r: &Matrix<f64>, h: Matrix<f64>) -> KF {
+    KF {
+        q: q.clone(),
+        r: r.clone(),
+        h,
+        f: Matrix::identity(5),
+        x0: Vector::zeros(5),
+        p0: Matrix::identity(5),
+    }
+}
+
+fn outer(v: &Vector<f64>) -> Matrix<f64> {
+    let column = Matrix::new(v.size(), 1, v.data().clone());
+    &column * column.transpose()
+}
+
+impl ImmFilter {
+    /// Creates a new ImmFilter, both models use the noise matrices of the settings.
+    pub fn from_settings(settings: &KalmanSettings) -> Result<ImmFilter, KalmanError> {
+        let q = covariance_matrix("Q", 5, &settings.q)?;
+        let r = covariance_matrix("R", 3, &settings.r)?;
+        let p = covariance_matrix("P0", 5, &[settings.sigma0; 5])?;
+        let r_position = r.select(&[0, 1], &[0, 1]);
+        let h = Matrix::new(3, 5, vec![1.0, 0.0, 0.0, 0.0, 0.0,
+                                       0.0, 1.0, 0.0, 0.0, 0.0,
+                                       0.0, 0.0, 1.0, 0.0, 0.0]);
+        let h_position = h.select(&[0, 1], &[0, 1, 2, 3, 4]);
+        let (to_moving, to_stationary) = (settings.imm_to_moving, settings.imm_to_stationary);
+        if !(0.0..=1.0).contains(&to_moving) {
+            return Err(KalmanError::NotAProbability("The probability of starting to move"));
+        }
+        if !(0.0..=1.0).contains(&to_stationary) {
+            return Err(KalmanError::NotAProbability("The probability of stopping"));
+        }
+
+        Ok(ImmFilter {
+            models: [model(&q, &r, h.clone()), model(&q, &r, h)],
+            position_models: [model(&q, &r_position, h_position.clone()), model(&q, &r_position, h_position)],
+            states: [KS { x: Vector::zeros(5), p: p.clone() }, KS { x: Vector::zeros(5), p: p.clone() }],
+            probabilities: [0.5, 0.5],
+            switching: [[1.0 - to_moving, to_moving],
+                        [to_stationary, 1.0 - to_stationary]],
+            base_q: q,
+            est_v_loss: settings.est_v_loss,
+            sigma_gain: settings.sigma_gain,
+            measurement_variance: (r[[0, 0]] + r[[1, 1]]) / 2.0,
+            state: KS { x: Vector::zeros(5), p },
+            point: None,
+            missed_frames: 0,
+            max_coast_frames: settings.max_coast_frames,
+            clock: FrameClock::new(settings.frame_time),
+        })
+    }
+
+    /// Measures the time between the updates with the timestamps of the frames, see FrameTimestamp.
+    pub fn with_frame_timestamps(mut self, timestamps: FrameTimestamp) -> ImmFilter {
+        self.clock.set_timestamps(timestamps);
+        self
+    }
+
+    /// Probability of the (stationary, moving) models, for logging and drawing.
+    pub fn get_model_probabilities(&self) -> (f64, f64) {
+        (self.probabilities[STATIONARY], self.probabilities[MOVING])
+    }
+
+    fn set_time_step(&mut self, dt: f64) {
+        let v_loss = self.est_v_loss.powf(dt);
+        // The stationary model forgets its velocity, the moving one keeps it
+        let stationary = Matrix::from_diag(&[1.0, 1.0, 1.0, 0.0, 0.0]);
+        let moving = Matrix::new(5, 5, vec![ 1.0, 0.0, 0.0, dt, 0.0,
+                    0.0, 1.0, 0.0, 0.0, dt,
+                    0.0, 0.0, 1.0, 0.0, 0.0,
+                    0.0, 0.0, 0.0, v_loss, 0.0,
+                    0.0, 0.0, 0.0, 0.0, v_loss ]);
+        let q = &self.base_q * dt;
+        for (i, f) in [stationary, moving].iter().enumerate() {
+            self.models[i].f = f.clone();
+            self.models[i].q = q.clone();
+            self.position_models[i].f = f.clone();
+            self.position_models[i].q = q.clone();
+        }
+    }
+
+    /// Mixes the states of the models according to the switching probabilities,
+    /// returns the mixed states and the predicted model probabilities.
+    fn mix(&self) -> ([KS; 2], [f64; 2]) {
+        let mut predicted = [0.0; 2];
+        for j in 0..2 {
+            predicted[j] = (0..2).map(|i| self.switching[i][j] * self.probabilities[i]).sum();
+        }
+
+        let mixed_state = |j: usize| {
+            let weights: Vec<f64> = (0..2)
+                .map(|i| if predicted[j] > 0.0 { self.switching[i][j] * self.probabilities[i] / predicted[j] } else { 0.5 })
+                .collect();
+            let mut x = &self.states[0].x * weights[0] + &self.states[1].x * weights[1];
+            // The angles are averaged around the first one, so that +-PI doesn't cancel out
+            let angle_diff = normalize_angle(self.states[1].x[2] - self.states[0].x[2]);
+            x[2] = normalize_angle(self.states[0].x[2] + weights[1] * angle_diff);
+
+            let mut p = Matrix::zeros(5, 5);
+            for i in 0..2 {
+                let mut diff = &self.states[i].x - &x;
+                diff[2] = normalize_angle(diff[2]);
+                p = p + (&self.states[i].p + outer(&diff)) * weights[i];
+            }
+            KS { x, p }
+        };
+
+        ([mixed_state(0), mixed_state(1)], predicted)
+    }
+
+    /// Combines the estimates of the models into a single one.
+    fn combine(&mut self) {
+        let mu = self.probabilities;
+        let mut x = &self.states[0].x * mu[0] + &self.states[1].x * mu[1];
+        let angle_diff = normalize_angle(self.states[1].x[2] - self.states[0].x[2]);
+        x[2] = normalize_angle(self.states[0].x[2] + mu[1] * angle_diff);
+
+        let mut p = Matrix::zeros(5, 5);
+        for i in 0..2 {
+            let mut diff = &self.states[i].x - &x;
+            diff[2] = normalize_angle(diff[2]);
+            p = p + (&self.states[i].p + outer(&diff)) * mu[i];
+        }
+        self.state = KS { x, p };
+    }
+
+    /// Updates the estimation with a measurement taken dt frames after the previous one.
+    pub fn update_estimation_dt(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, dt: f64) {
+        self.set_time_step(dt);
+        let (mixed, predicted) = self.mix();
+
+        match point {
+            Some(p) => {
+                let mut likelihoods = [0.0; 2];
+                let mut states = Vec::with_capacity(2);
+                for (j, init) in mixed.iter().enumerate() {
+                    let model = if angle.is_some() { &self.models[j] } else { &self.position_models[j] };
+                    let pred = predict_step(model, init);
+                    let measurement = match angle {
+                        Some(a) => vector![p.x as f64, p.y as f64, pred.x[2] + normalize_angle(a - pred.x[2])],
+                        None => vector![p.x as f64, p.y as f64],
+                    };
+
+                    let s = &model.h * &pred.p * model.h.transpose() + &model.r;
+                    let norm = ((2.0 * PI).powi(s.rows() as i32) * s.det()).sqrt();
+                    likelihoods[j] = match mahalanobis_distance(model, &pred, &measurement) {
+                        Some(distance) if norm > 0.0 => (-0.5 * distance).exp() / norm,
+                        _ => 0.0,
+                    };
+
+                    let mut next = update_step(model, &pred, &measurement);
+                    next.x[2] = normalize_angle(next.x[2]);
+                    states.push(next);
+                }
+
+                let total: f64 = (0..2).map(|j| predicted[j] * likelihoods[j]).sum();
+                for j in 0..2 {
+                    self.probabilities[j] = if total > 0.0 { predicted[j] * likelihoods[j] / total } else { predicted[j] };
+                }
+                let moving = states.pop().unwrap();
+                let stationary = states.pop().unwrap();
+                self.states = [stationary, moving];
+                self.combine();
+
+                self.point = Some(GeometricPoint::new(self.state.x[0] as i32, self.state.x[1] as i32));
+                self.missed_frames = 0;
+            }
+            None => {
+                let gain = self.sigma_gain.powf(dt);
+                if self.point.is_none() {
+                    for s in self.states.iter_mut() {
+                        s.p = &s.p * gain;
+                    }
+                    self.combine();
+                    return;
+                }
+                let mut states = mixed.iter().enumerate()
+                    .map(|(j, init)| {
+                        let mut pred = predict_step(&self.models[j], init);
+                        pred.p = &pred.p * gain;
+                        pred
+                    })
+                    .collect::<Vec<KS>>();
+                self.probabilities = predicted;
+                let moving = states.pop().unwrap();
+                let stationary = states.pop().unwrap();
+                self.states = [stationary, moving];
+                self.combine();
+                self.missed_frames += 1;
+
+                self.point = if self.missed_frames > self.max_coast_frames {
+                    None
+                } else {
+                    Some(GeometricPoint::new(self.state.x[0] as i32, self.state.x[1] as i32))
+                };
+            }
+        }
+    }
+}
+
+impl Filter for ImmFilter {
+    fn update_estimation(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, _cert: f64) {
+        let dt = self.clock.elapsed_frames();
+        self.update_estimation_dt(point, angle, dt);
+    }
+
+    fn get_estimated_position(&self) -> Option<GeometricPoint> {
+        self.point.as_ref().map(|p| p.clone())
+    }
+
+    fn get_estimated_angle(&self) -> f64 {
+        self.state.x[2]
+    }
+
+    fn get_estimated_vx(&self) -> f64 {
+        self.state.x[3]
+    }
+
+    fn get_estimated_vy(&self) -> f64 {
+        self.state.x[4]
+    }
+
+    fn get_estimation_certainty(&self) -> f64 {
+        if self.point.is_none() {
+            return 0.0;
+        }
+        position_certainty(&self.state.p, self.measurement_variance)
+    }
+
+    fn draw_on_image(&self, m_d: &mut MarkerDrawer) {
+        if let Some(p) = &self.point {
+            m_d.point(p, get_blue());
+
+            let k = 10.0;
+            let angle = self.state.x[2];
+            let other_point = GeometricPoint::new(p.x + (k * angle.cos()) as i32, p.y + (k * angle.sin()) as i32);
+            m_d.line(p, &other_point, Scalar::new(255.0, 255.0, 255.0, 255.0));
+
+            // Model probabilities under the estimate: green is moving, red is stationary
+            let width = 40.0;
+            let start = GeometricPoint::new(p.x - width as i32 / 2, p.y - 20);
+            let split = GeometricPoint::new(start.x + (width * self.probabilities[MOVING]) as i32, start.y);
+            let end = GeometricPoint::new(start.x + width as i32, start.y);
+            m_d.line(&start, &split, get_green());
+            m_d.line(&split, &end, get_red());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kalman_filter::KalmanFilter;
+
+    const SPEED: f64 = 3.0;
+    const STOP_FRAME: usize = 60;
+
+    /// The hat walks to the right and stops, returns where it is at the given frame.
+    fn walk_and_stop(frame: usize) -> GeometricPoint {
+        let x = SPEED * frame.min(STOP_FRAME) as f64;
+        GeometricPoint::new(x as i32, 100)
+    }
+
+    /// A hat that walks and then stops switches the IMM to the stationary model, and it overshoots
+    /// the stopping point less than the KalmanFilter with its constant velocity model.
+    #[test]
+    fn move_then_stop_test() {
+        let settings = KalmanSettings::default();
+        let mut imm = ImmFilter::from_settings(&settings).unwrap();
+        let mut kalman = KalmanFilter::from_settings(&settings).unwrap();
+        let stop_x = walk_and_stop(STOP_FRAME).x;
+        let (mut imm_overshoot, mut kalman_overshoot) = (0, 0);
+
+        for frame in 0..(2 * STOP_FRAME) {
+            imm.update_estimation_dt(Some(walk_and_stop(frame)), Some(0.0), 1.0);
+            kalman.update_estimation_dt(Some(walk_and_stop(frame)), Some(0.0), 1.0);
+
+            if frame == STOP_FRAME - 1 {
+                let (stationary, moving) = imm.get_model_probabilities();
+                assert!(moving > stationary, "The walking hat is not taken as moving ({} / {})", stationary, moving);
+            }
+            if frame >= STOP_FRAME {
+                imm_overshoot = imm_overshoot.max(imm.get_estimated_position().unwrap().x - stop_x);
+                kalman_overshoot = kalman_overshoot.max(kalman.get_estimated_position().unwrap().x - stop_x);
+            }
+        }
+
+        let (stationary, moving) = imm.get_model_probabilities();
+        assert!(stationary > moving, "The stopped hat is not taken as stationary ({} / {})", stationary, moving);
+        assert!((stationary + moving - 1.0).abs() < 1e-9, "The model probabilities don't add up to 1!");
+        assert!(imm_overshoot < kalman_overshoot,
+                "The ImmFilter overshot by {} px, the KalmanFilter by {} px", imm_overshoot, kalman_overshoot);
+    }
+}
@@ -1,7 +1,12 @@
+use std::{fmt, fs, io};
+use std::f64::consts::PI;
+use std::time::Instant;
+use std::sync::{Arc, Mutex};
+
 use rulinalg::vector::Vector;
-use rulinalg::matrix::{Matrix};
+use rulinalg::matrix::{Matrix, BaseMatrix};
 
-use linearkalman::{KalmanFilter as KF, filter_step};
+use linearkalman::{KalmanFilter as KF, filter_step, predict_step};
 use linearkalman::KalmanState as KS;
 
 use opencv::core::Scalar;
@@ -9,46 +14,319 @@ use opencv::core::Scalar;
 use rust_drone_follow::traits::Filter;
 use rust_drone_follow::models::GeometricPoint;
 use rust_drone_follow::utils::MarkerDrawer;
-use rust_drone_follow::utils::opencv_custom::get_blue;
+use rust_drone_follow::utils::opencv_custom::{get_blue, get_green, get_red};
+
+
+/// Tunable parameters of the KalmanFilter, as read from config.kalman.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KalmanSettings {
+    /// Uncertainty of the initial estimation (p0 will be an eye(sigma0))
+    pub sigma0: f64,
+    /// Factor with which uncertainty grows in case the hat is not detected on the screen (1.0 means no change)
+    pub sigma_gain: f64,
+    /// Factor with which the filter estimates loss in vx, vy (1.0 means no change)
+    pub est_v_loss: f64,
+    /// Process noise covariance (Q): either the 5 diagonal values or the full 5x5 matrix (row-major)
+    pub q: Vec<f64>,
+    /// Measurement noise covariance (R): either the 3 diagonal values or the full 3x3 matrix (row-major)
+    pub r: Vec<f64>,
+    /// Number of frames the position is predicted without detecting the hat, before it is reported as lost
+    pub max_coast_frames: usize,
+    /// Nominal time between two frames in seconds, velocities are measured in pixels per frame_time.
+    /// If it is not positive, every update is taken to be exactly one frame apart.
+    pub frame_time: f64,
+    /// Measurements further from the prediction than this (squared Mahalanobis distance) are rejected,
+    /// 0.0 turns the gate off
+    pub gate_threshold: f64,
+    /// Number of particles used by the ParticleFilter
+    pub particle_count: usize,
+    /// Seed of the random number generator of the ParticleFilter, so that runs can be reproduced
+    pub seed: u64,
+    /// Probability of the ImmFilter switching from the stationary to the moving model in one frame
+    pub imm_to_moving: f64,
+    /// Probability of the ImmFilter switching from the moving to the stationary model in one frame
+    pub imm_to_stationary: f64,
+    /// Start the KalmanFilter from the snapshot saved when the previous run was stopped
+    pub warm_start: bool,
+}
+
+impl Default for KalmanSettings {
+    fn default() -> KalmanSettings {
+        KalmanSettings {
+            sigma0: 1.0,
+            sigma_gain: 1.1,
+            est_v_loss: 1.0,
+            q: vec![1.0, 1.0, 2.0, 1.0, 1.0],
+            r: vec![10.0, 10.0, 0.001],
+            max_coast_frames: 30,
+            frame_time: 1.0 / 30.0,
+            gate_threshold: 0.0,
+            particle_count: 500,
+            seed: 42,
+            imm_to_moving: 0.05,
+            imm_to_stationary: 0.05,
+            warm_start: false,
+        }
+    }
+}
+
+/// Maps an angle into the [-PI, PI) interval.
+pub fn normalize_angle(a: f64) -> f64 {
+    a - 2.0 * PI * ((a + PI) / (2.0 * PI)).floor()
+}
+
+/// Errors that can occur when building a KalmanFilter from user supplied settings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KalmanError {
+    /// The matrix (name, dimension) was given with the wrong number of values.
+    InvalidShape(&'static str, usize, usize),
+    /// The matrix contains values that are not finite numbers.
+    NotANumber(&'static str),
+    /// The matrix is not symmetric positive-definite.
+    NotPositiveDefinite(&'static str),
+    /// The value should be a probability between 0 and 1.
+    NotAProbability(&'static str),
+    /// The saved filter state can't be used (missing file, other version or corrupted values).
+    InvalidSnapshot(String),
+}
+
+impl fmt::Display for KalmanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KalmanError::InvalidShape(name, dim, found) => write!(f,
+                "{} needs {} (diagonal) or {} (full matrix) values, but {} were given", name, dim, dim * dim, found),
+            KalmanError::NotANumber(name) => write!(f, "{} contains values that are not numbers", name),
+            KalmanError::NotPositiveDefinite(name) => write!(f, "{} is not symmetric positive-definite", name),
+            KalmanError::NotAProbability(name) => write!(f, "{} should be between 0 and 1", name),
+            KalmanError::InvalidSnapshot(reason) => write!(f, "the filter snapshot is invalid: {}", reason),
+        }
+    }
+}
+
+/// Builds a dim x dim covariance matrix from either its diagonal or all of its values and
+/// checks that it can be used as a covariance matrix.
+pub(crate) fn covariance_matrix(name: &'static str, dim: usize, values: &[f64]) -> Result<Matrix<f64>, KalmanError> {
+    let m = if values.len() == dim {
+        Matrix::from_diag(values)
+    } else if values.len() == dim * dim {
+        Matrix::new(dim, dim, values.to_vec())
+    } else {
+        return Err(KalmanError::InvalidShape(name, dim, values.len()));
+    };
+
+    if values.iter().any(|v| !v.is_finite()) {
+        return Err(KalmanError::NotANumber(name));
+    }
+    if !is_positive_definite(&m) {
+        return Err(KalmanError::NotPositiveDefinite(name));
+    }
+    Ok(m)
+}
+
+fn is_positive_definite(m: &Matrix<f64>) -> bool {
+    let symmetric = (0..m.rows())
+        .all(|i| (0..i).all(|j| (m[[i, j]] - m[[j, i]]).abs() <= 1e-9 * (1.0 + m[[i, j]].abs())));
+
+    // A failed Cholesky decomposition either errors out or leaves NaNs / zeros on the diagonal
+    symmetric && match m.cholesky() {
+        Ok(l) => l.data().iter().all(|v| v.is_finite()) && (0..l.rows()).all(|i| l[[i, i]] > 0.0),
+        Err(_) => false,
+    }
+}
+
+/// Limits of the elapsed time between updates (in frames), so that bunched frames or a long
+/// pause in the video stream can't make the prediction explode.
+const MIN_TIME_STEP: f64 = 0.1;
+const MAX_TIME_STEP: f64 = 10.0;
+
+/// The time (in seconds) at which the current frame was taken, set by the controller on every frame
+/// and read by the filter, as the HatFollower doesn't hand it over. None until the first frame.
+pub type FrameTimestamp = Arc<Mutex<Option<f64>>>;
+
+/// The time between two frame timestamps (in seconds) in frames, the way the filters step on them.
+/// The smoother uses it too, so that a logged session is replayed with the same steps.
+pub(crate) fn frames_between(frame_time: f64, last: f64, now: f64) -> f64 {
+    // The timestamps restart when the video stream is reopened
+    if frame_time > 0.0 && now >= last {
+        ((now - last) / frame_time).min(MAX_TIME_STEP)
+    } else {
+        1.0
+    }
+}
 
+/// Measures the time between updates of a filter in (nominal) frames: from the timestamps of the frames
+/// if the controller provides them, otherwise from the wall clock.
+pub(crate) struct FrameClock {
+    frame_time: f64,
+    last_update: Option<Instant>,
+    timestamps: Option<FrameTimestamp>,
+    last_timestamp: Option<f64>,
+}
+
+impl FrameClock {
+    pub(crate) fn new(frame_time: f64) -> FrameClock {
+        FrameClock {
+            frame_time,
+            last_update: None,
+            timestamps: None,
+            last_timestamp: None,
+        }
+    }
+
+    /// Reads the time of the frames from the given timestamps instead of the wall clock.
+    pub(crate) fn set_timestamps(&mut self, timestamps: FrameTimestamp) {
+        self.timestamps = Some(timestamps);
+    }
+
+    /// Returns the time elapsed since the last call in frames (1.0 if there is no usable clock).
+    pub(crate) fn elapsed_frames(&mut self) -> f64 {
+        if let Some(timestamps) = &self.timestamps {
+            let timestamp = timestamps.lock().ok().and_then(|t| *t);
+            let dt = match (self.last_timestamp, timestamp) {
+                (Some(last), Some(now)) => frames_between(self.frame_time, last, now),
+                _ => 1.0,
+            };
+            self.last_timestamp = timestamp;
+            return dt;
+        }
+
+        let now = Instant::now();
+        let dt = match self.last_update {
+            Some(last) if self.frame_time > 0.0 => {
+                let elapsed = now.duration_since(last);
+                let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+                (seconds / self.frame_time).max(MIN_TIME_STEP).min(MAX_TIME_STEP)
+            }
+            _ => 1.0,
+        };
+        self.last_update = Some(now);
+        dt
+    }
+}
+
+/// Squared Mahalanobis distance of the measurement from the predicted state, with the
+/// observation model of the given filter.
+pub(crate) fn mahalanobis_distance(filter: &KF, pred: &KS, measurement: &Vector<f64>) -> Option<f64> {
+    let innovation = measurement - &filter.h * &pred.x;
+    let s = &filter.h * &pred.p * filter.h.transpose() + &filter.r;
+
+    s.inverse().ok().map(|s_inv| innovation.dot(&(s_inv * &innovation)))
+}
+
+/// Compares the variance of the estimated position to that of a single measurement:
+/// it is 1.0 while the estimate is at least as good as a detection, and goes towards 0.0
+/// as the uncertainty grows while the hat is not seen.
+pub(crate) fn position_certainty(p: &Matrix<f64>, measurement_variance: f64) -> f64 {
+    let variance = (p[[0, 0]] + p[[1, 1]]) / 2.0;
+    if variance <= 0.0 {
+        return 1.0;
+    }
+    (measurement_variance / variance).min(1.0)
+}
+
+/// Draws the k-sigma ellipse of the position block of the covariance matrix around the center.
+fn draw_covariance_ellipse(m_d: &mut MarkerDrawer, center: &GeometricPoint, p: &Matrix<f64>, k: f64, color: Scalar) {
+    let (a, b, c) = (p[[0, 0]], p[[0, 1]], p[[1, 1]]);
+    // Eigenvalues and the orientation of the major axis of the 2x2 block
+    let mean = (a + c) / 2.0;
+    let diff = (((a - c) / 2.0).powi(2) + b * b).sqrt();
+    let major = k * (mean + diff).max(0.0).sqrt();
+    let minor = k * (mean - diff).max(0.0).sqrt();
+    let rotation = 0.5 * (2.0 * b).atan2(a - c);
+
+    let segments = 24;
+    let ellipse_point = |i: i32| {
+        let t = 2.0 * PI * i as f64 / segments as f64;
+        let (x, y) = (major * t.cos(), minor * t.sin());
+        GeometricPoint::new(
+            center.x + (x * rotation.cos() - y * rotation.sin()) as i32,
+            center.y + (x * rotation.sin() + y * rotation.cos()) as i32
+        )
+    };
+    for i in 0..segments {
+        m_d.line(&ellipse_point(i), &ellipse_point(i + 1), color);
+    }
+}
+
+/// File in which the state of the KalmanFilter is kept between runs.
+pub const SNAPSHOT_FILE: &str = "kalman.snapshot";
+/// First line of the snapshot files, it has to change whenever the layout of the state changes.
+const SNAPSHOT_VERSION: &str = "kalman_snapshot v1";
 
 pub struct KalmanFilter {
     filter: KF,
+    position_filter: KF,
     state: KS,
     point: Option<GeometricPoint>,
     angle: f64,
     sigma_gain: f64,
+    measurement_variance: f64,
+    missed_frames: usize,
+    max_coast_frames: usize,
+    base_q: Matrix<f64>,
+    est_v_loss: f64,
+    clock: FrameClock,
+    gate_threshold: f64,
+    rejected_count: usize,
+    measurement: Option<GeometricPoint>,
+    snapshot_file: Option<String>,
 }
 
 impl KalmanFilter {
-    /// Creates a new KalmanFilter with the given properties.
+    /// Creates a new KalmanFilter with the given properties and the default noise matrices.
     /// Parameters:
     /// sigma0: is the uncertainty of the initial estimation (p0 will be an eye(sigma0))
     /// sigma_gain: is the factor with which uncertainty grows in case the hat is not detected on the screen (1.0 means no change)
     /// est_v_loss: is the factor with which the Kalman Filter estimates loss in vx, vy (due to the drone adapting to the moving hat) (1.0 means no change)
     pub fn new(sigma0: f64, sigma_gain: f64, est_v_loss: f64) -> KalmanFilter {
+        let settings = KalmanSettings {
+            sigma0,
+            sigma_gain,
+            est_v_loss,
+            ..KalmanSettings::default()
+        };
+        let q = Matrix::from_diag(&settings.q);
+        let r = Matrix::from_diag(&settings.r);
+        KalmanFilter::build(&settings, q, r)
+    }
+
+    /// Creates a new KalmanFilter from the given settings, checking that the noise matrices
+    /// have the right shape and are positive-definite.
+    pub fn from_settings(settings: &KalmanSettings) -> Result<KalmanFilter, KalmanError> {
+        let q = covariance_matrix("Q", 5, &settings.q)?;
+        let r = covariance_matrix("R", 3, &settings.r)?;
+        covariance_matrix("P0", 5, &[settings.sigma0; 5])?;
+
+        Ok(KalmanFilter::build(settings, q, r))
+    }
+
+    fn build(settings: &KalmanSettings, q: Matrix<f64>, r: Matrix<f64>) -> KalmanFilter {
+        let KalmanSettings { sigma0, sigma_gain, est_v_loss, max_coast_frames, frame_time, gate_threshold, .. } = *settings;
+        let measurement_variance = (r[[0, 0]] + r[[1, 1]]) / 2.0;
+        let base_q = q.clone();
+        let position_filter = KF {
+            q: q.clone(),
+            // Only the x, y part of the measurement noise
+            r: r.select(&[0, 1], &[0, 1]),
+            // Observation matrix without the angle
+            h: Matrix::new(2, 5, vec![1.0, 0.0, 0.0, 0.0, 0.0,
+                    0.0, 1.0, 0.0, 0.0, 0.0]),
+            f: KalmanFilter::transition_matrix(est_v_loss, 1.0),
+            x0: Vector::new(vec![ 0.0, 0.0, 0.0, 0.0, 0.0 ]),
+            p0: Matrix::identity(5),
+        };
         KalmanFilter {
             filter : KF {
                 // Process noise covariance
-                q: Matrix::new(5, 5, vec![ 1.0, 0.0, 0.0, 0.0, 0.0,
-                                           0.0, 1.0, 0.0, 0.0, 0.0,
-                                           0.0, 0.0, 2.0, 0.0, 0.0,
-                                           0.0, 0.0, 0.0, 1.0, 0.0,
-                                           0.0, 0.0, 0.0, 0.0, 1.0 ]),
+                q,
                 // Measurement noise matrix
-                r: Matrix::new(3, 3, vec![ 10.0, 0.0, 0.0,
-                                           0.0, 10.0, 0.0,
-                                           0.0, 0.0, 0.001 ]),
+                r,
                 // Observation matrix
                 h: Matrix::new(3, 5, vec![1.0, 0.0, 0.0, 0.0, 0.0,
                         0.0, 1.0, 0.0, 0.0, 0.0,
                         0.0, 0.0, 1.0, 0.0, 0.0]),
                 // State transition matrix
-                f: Matrix::new(5, 5, vec![ 1.0, 0.0, 0.0, 1.0, 0.0,
-                            0.0, 1.0, 0.0, 0.0, 1.0,
-                            0.0, 0.0, 1.0, 0.0, 0.0,
-                            0.0, 0.0, 0.0, est_v_loss, 0.0,
-                            0.0, 0.0, 0.0, 0.0, est_v_loss ]),
+                f: KalmanFilter::transition_matrix(est_v_loss, 1.0),
                 // Initial guess for state mean at time 1
                 x0: Vector::new(vec![ 0.0, 0.0, 0.0, 0.0, 0.0 ]),
                 // Initial guess for state covariance at time 1
@@ -68,33 +346,188 @@ impl KalmanFilter {
                                           0.0, 0.0, 0.0, sigma0, 0.0,
                                           0.0, 0.0, 0.0, 0.0, sigma0]),
             },
+            position_filter,
             point: None,
             angle: 0.0,
             sigma_gain,
+            measurement_variance,
+            missed_frames: 0,
+            max_coast_frames,
+            base_q,
+            est_v_loss,
+            clock: FrameClock::new(frame_time),
+            gate_threshold,
+            rejected_count: 0,
+            measurement: None,
+            snapshot_file: None,
         }
     }
-}
 
-impl Filter for KalmanFilter {
-    fn update_estimation(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, _cert: f64) {
+    /// Measures the time between the updates with the timestamps of the frames, see FrameTimestamp.
+    pub fn with_frame_timestamps(mut self, timestamps: FrameTimestamp) -> KalmanFilter {
+        self.clock.set_timestamps(timestamps);
+        self
+    }
+
+    /// The state of the filter will be saved to the given file when it is dropped
+    /// (when the follower is stopped), so that the next run can be warm-started from it.
+    pub fn with_snapshot_file(mut self, filename: &str) -> KalmanFilter {
+        self.snapshot_file = Some(String::from(filename));
+        self
+    }
+
+    /// Saves the state (x, P) and the last angle of the filter.
+    /// Format: the version tag, the 5 values of x, the 25 values of P (row-major) and the angle, each on its own line.
+    pub fn save_snapshot(&self, filename: &str) -> io::Result<()> {
+        let join = |values: &[f64]| values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ");
+        let content = format!("{}\n{}\n{}\n{}\n", SNAPSHOT_VERSION,
+            join(&self.state.x.data()[..]), join(&self.state.p.data()[..]), self.angle);
+        fs::write(filename, content)
+    }
+
+    /// Restores the state saved by save_snapshot. Snapshots with a different version tag,
+    /// missing values or a covariance that is not positive-definite are rejected and
+    /// leave the filter unchanged.
+    pub fn load_snapshot(&mut self, filename: &str) -> Result<(), KalmanError> {
+        let content = fs::read_to_string(filename)
+            .map_err(|e| KalmanError::InvalidSnapshot(format!("{} could not be read ({})", filename, e)))?;
+        let lines: Vec<&str> = content.lines().collect();
+
+        match lines.first().map(|l| l.trim()) {
+            Some(SNAPSHOT_VERSION) => (),
+            Some(other) => return Err(KalmanError::InvalidSnapshot(
+                format!("expected version \"{}\", found \"{}\"", SNAPSHOT_VERSION, other))),
+            None => return Err(KalmanError::InvalidSnapshot(String::from("the file is empty"))),
+        }
+
+        let values = |i: usize, count: usize, name: &str| {
+            let v: Vec<f64> = lines.get(i).map(|l| crate::utils::file_readers::parse_values(l)).unwrap_or_default();
+            if v.len() != count || v.iter().any(|x| !x.is_finite()) {
+                Err(KalmanError::InvalidSnapshot(format!("{} needs {} numbers", name, count)))
+            } else {
+                Ok(v)
+            }
+        };
+        let x = values(1, 5, "x")?;
+        let p = Matrix::new(5, 5, values(2, 25, "P")?);
+        let angle = values(3, 1, "the angle")?[0];
+        if !is_positive_definite(&p) {
+            return Err(KalmanError::InvalidSnapshot(String::from("P is not symmetric positive-definite")));
+        }
+
+        self.point = Some(GeometricPoint::new(x[0] as i32, x[1] as i32));
+        self.angle = normalize_angle(angle);
+        self.state = KS { x: Vector::new(x), p };
+        self.missed_frames = 0;
+        Ok(())
+    }
+
+    /// State transition for a time step of dt frames
+    pub(crate) fn transition_matrix(est_v_loss: f64, dt: f64) -> Matrix<f64> {
+        let v_loss = est_v_loss.powf(dt);
+        Matrix::new(5, 5, vec![ 1.0, 0.0, 0.0, dt, 0.0,
+                    0.0, 1.0, 0.0, 0.0, dt,
+                    0.0, 0.0, 1.0, 0.0, 0.0,
+                    0.0, 0.0, 0.0, v_loss, 0.0,
+                    0.0, 0.0, 0.0, 0.0, v_loss ])
+    }
+
+    /// Rebuilds the state transition and process noise for a time step of dt frames.
+    /// The configured Q is the noise of a single frame, so it grows linearly with dt.
+    fn set_time_step(&mut self, dt: f64) {
+        let f = KalmanFilter::transition_matrix(self.est_v_loss, dt);
+        let q = &self.base_q * dt;
+        self.position_filter.f = f.clone();
+        self.position_filter.q = q.clone();
+        self.filter.f = f;
+        self.filter.q = q;
+    }
+
+    /// Number of measurements that were rejected by the gate since the filter was created.
+    pub fn get_rejected_count(&self) -> usize {
+        self.rejected_count
+    }
+
+    /// Updates the estimation with a measurement taken dt frames after the previous one.
+    /// This can be used directly when the frames have their own timestamps.
+    pub fn update_estimation_dt(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, dt: f64) {
+        self.set_time_step(dt);
+        self.measurement = point.clone();
         match point {
             Some(p) => {
-                if let Some(a) = angle {
-                    let (next, _pred) = filter_step(&self.filter,
-                                                    &self.state,
-                                                    &vector![p.x as f64, p.y as f64, a],
-                    );
-
-                    self.point = Some(GeometricPoint::new(next.x[0] as i32, next.x[1] as i32));
-                    self.angle = next.x[2];
-                    self.state = next;
+                let measurement = match angle {
+                    // The measured angle is moved next to the estimated one, so that
+                    // crossing the +-PI boundary doesn't look like a half turn
+                    Some(a) => vector![p.x as f64, p.y as f64, self.state.x[2] + normalize_angle(a - self.state.x[2])],
+                    // The detector found the hat, but not its orientation, so only x, y is corrected
+                    None => vector![p.x as f64, p.y as f64],
+                };
+                let filter = if angle.is_some() { &self.filter } else { &self.position_filter };
+
+                // Measurements far from a tracked hat are most likely false detections
+                if self.point.is_some() && self.gate_threshold > 0.0 {
+                    let pred = predict_step(filter, &self.state);
+                    if let Some(distance) = mahalanobis_distance(filter, &pred, &measurement) {
+                        if distance > self.gate_threshold {
+                            self.rejected_count += 1;
+                            self.coast(dt);
+                            return;
+                        }
+                    }
                 }
+
+                let (mut next, _pred) = filter_step(filter, &self.state, &measurement);
+                next.x[2] = normalize_angle(next.x[2]);
+
+                self.point = Some(GeometricPoint::new(next.x[0] as i32, next.x[1] as i32));
+                self.angle = next.x[2];
+                self.state = next;
+                self.missed_frames = 0;
             }
             None => {
-                self.state.p = &self.state.p * self.sigma_gain;
+                self.coast(dt);
+            }
+        }
+    }
+
+    /// Predicts the state without a measurement.
+    fn coast(&mut self, dt: f64) {
+        let gain = self.sigma_gain.powf(dt);
+        if self.point.is_none() {
+            self.state.p = &self.state.p * gain;
+            return;
+        }
+        // Coast along the estimated velocity while the hat is not detected
+        let mut pred = predict_step(&self.filter, &self.state);
+        pred.p = &pred.p * gain;
+        self.state = pred;
+        self.missed_frames += 1;
+
+        self.point = if self.missed_frames > self.max_coast_frames {
+            None
+        } else {
+            Some(GeometricPoint::new(self.state.x[0] as i32, self.state.x[1] as i32))
+        };
+    }
+}
+
+impl Drop for KalmanFilter {
+    /// Saves the state if a snapshot file was set. A filter that is not tracking
+    /// the hat when stopped doesn't overwrite the previous snapshot.
+    fn drop(&mut self) {
+        if let (Some(filename), Some(_)) = (&self.snapshot_file, &self.point) {
+            if let Err(e) = self.save_snapshot(filename) {
+                println!("Could not save the filter state to {}: {}", filename, e);
             }
         }
     }
+}
+
+impl Filter for KalmanFilter {
+    fn update_estimation(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, _cert: f64) {
+        let dt = self.clock.elapsed_frames();
+        self.update_estimation_dt(point, angle, dt);
+    }
 
     fn get_estimated_position(&self) -> Option<GeometricPoint> {
         self.point.as_ref().map(|p| p.clone())
@@ -113,10 +546,17 @@ impl Filter for KalmanFilter {
     }
 
     fn get_estimation_certainty(&self) -> f64 {
-        1.0
+        if self.point.is_none() {
+            return 0.0;
+        }
+        position_certainty(&self.state.p, self.measurement_variance)
     }
 
     fn draw_on_image(&self, m_d: &mut MarkerDrawer) {
+        // The raw detection, so that it can be compared to the filtered estimate
+        if let Some(m) = &self.measurement {
+            m_d.point(m, get_red());
+        }
         if let Some(p) = &self.point {
             m_d.point(p, get_blue());
 
@@ -124,6 +564,99 @@ impl Filter for KalmanFilter {
             let other_point = GeometricPoint::new(p.x + k, p.y + (k as f64 * self.angle.max(-1.57).min(1.57).tan()) as i32);
 
             m_d.line(p, &other_point, Scalar::new(255.0, 255.0, 255.0, 255.0));
+
+            // Where the hat is expected to be in 10 frames
+            let velocity_point = GeometricPoint::new(
+                p.x + (10.0 * self.state.x[3]) as i32,
+                p.y + (10.0 * self.state.x[4]) as i32
+            );
+            m_d.line(p, &velocity_point, get_green());
+
+            draw_covariance_ellipse(m_d, p, &self.state.p, 1.0, Scalar::new(0.0, 255.0, 255.0, 255.0));
+            draw_covariance_ellipse(m_d, p, &self.state.p, 2.0, Scalar::new(0.0, 165.0, 255.0, 255.0));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::traits::MoveTactic;
+    use crate::simulation::movetactics::stand_turn::StandTurn;
+
+    /// Feeds a linearly moving hat to the KalmanFilter without ever giving it an angle,
+    /// and checks that the estimated position still converges to the real one.
+    #[test]
+    fn position_only_test() {
+        let mut filter = KalmanFilter::from_settings(&KalmanSettings::default()).unwrap();
+
+        let (mut x, mut y) = (100.0, 50.0);
+        for _i in 0..200 {
+            x += 2.0;
+            y -= 1.0;
+            filter.update_estimation_dt(Some(GeometricPoint::new(x as i32, y as i32)), None, 1.0);
+        }
+
+        let estimation = filter.get_estimated_position().expect("No position was estimated!");
+        let error = ((estimation.x as f64 - x).powi(2) + (estimation.y as f64 - y).powi(2)).sqrt();
+        assert!(error < 3.0, "The estimation did not converge without angles (error: {})", error);
+    }
+
+    /// Turns the hat around twice with StandTurn and checks that the estimated angle follows it
+    /// when the measured heading jumps between PI and -PI.
+    #[test]
+    fn angle_wraparound_test() {
+        let mut filter = KalmanFilter::from_settings(&KalmanSettings::default()).unwrap();
+        let mut tactic = StandTurn::new(0.05);
+
+        let (mut x, mut y, mut a) = (200.0, 100.0, 0.0);
+        for i in 0..((4.0 * PI / 0.05) as usize) {
+            let (nx, ny, na) = tactic.execute_move(x, y, a);
+            x = nx;
+            y = ny;
+            a = na;
+            filter.update_estimation_dt(Some(GeometricPoint::new(x as i32, y as i32)), Some(normalize_angle(a)), 1.0);
+
+            let error = normalize_angle(filter.get_estimated_angle() - a).abs();
+            assert!(error < 0.1, "The estimated angle spun away at frame {} (error: {})", i, error);
+            assert!(filter.get_estimated_angle().abs() <= PI, "The estimated angle is not normalized!");
+        }
+
+        // A heading that keeps flipping sign around the boundary
+        let mut filter = KalmanFilter::from_settings(&KalmanSettings::default()).unwrap();
+        for i in 0..100 {
+            let a = if i % 2 == 0 { PI - 0.01 } else { -PI + 0.01 };
+            filter.update_estimation_dt(Some(GeometricPoint::new(200, 100)), Some(a), 1.0);
+        }
+        let error = normalize_angle(filter.get_estimated_angle() - PI).abs();
+        assert!(error < 0.05, "The estimated angle left the boundary (error: {})", error);
+    }
+
+    /// Saves the state of a tracking KalmanFilter, restores it into a fresh one and checks that
+    /// a snapshot with a different version tag is rejected.
+    #[test]
+    fn snapshot_test() {
+        let path = std::env::temp_dir().join(format!("parrot_hat_follow_{}.snapshot", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let mut filter = KalmanFilter::from_settings(&KalmanSettings::default()).unwrap();
+        for i in 0..50 {
+            filter.update_estimation_dt(Some(GeometricPoint::new(100 + 2 * i, 50 - i)), Some(0.5), 1.0);
+        }
+        filter.save_snapshot(filename).expect("Could not save the snapshot!");
+
+        let mut restored = KalmanFilter::from_settings(&KalmanSettings::default()).unwrap();
+        restored.load_snapshot(filename).expect("Could not load the snapshot!");
+        let position = |f: &KalmanFilter| f.get_estimated_position().map(|p| (p.x, p.y));
+        assert_eq!(position(&filter), position(&restored));
+        assert!((filter.get_estimated_vx() - restored.get_estimated_vx()).abs() < 1e-9);
+        assert!((filter.get_estimation_certainty() - restored.get_estimation_certainty()).abs() < 1e-9);
+
+        let content = fs::read_to_string(filename).unwrap();
+        fs::write(filename, content.replacen("v1", "v0", 1)).unwrap();
+        let mut rejected = KalmanFilter::from_settings(&KalmanSettings::default()).unwrap();
+        let result = rejected.load_snapshot(filename);
+        fs::remove_file(filename).unwrap();
+        assert!(result.is_err(), "A snapshot of another version was accepted!");
+        assert_eq!(position(&rejected), None);
+    }
+}
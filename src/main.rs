@@ -4,14 +4,69 @@ extern crate linearkalman;
 
 mod parrot;
 mod kalman_filter;
+mod ctrv_filter;
+mod particle_filter;
+mod imm_filter;
 mod simulation;
 mod ui;
 mod utils;
 
-use iced::{Settings, Sandbox};
+use std::env;
+use std::path::Path;
+
+use iced::{Settings, Application};
 use ui::tour::Tour;
 
+use kalman_filter::KalmanSettings;
+use utils::file_readers::read_kalman_file;
+use utils::smoother::smooth_measurement_log;
+use utils::calibration::run_calibration;
+use simulation::drone_emulator::{DroneEmulator, LOCAL_ADDRESS};
+use simulation::virtual_controller::VirtualController;
+use simulation::movetactics::move_squares::MoveSquares;
+use simulation::windtactics::random_wind::RandomWind;
+
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 4 && args[1] == "smooth" {
+        // parrot_hat_follow smooth measurements_123.txt smoothed_123.csv
+        let settings = if Path::new("config.kalman").exists() {
+            read_kalman_file("config.kalman").1
+        } else {
+            KalmanSettings::default()
+        };
+        match smooth_measurement_log(args[2].as_str(), args[3].as_str(), &settings) {
+            Ok(rows) => println!("Saved {} smoothed states to {}", rows, args[3]),
+            Err(e) => println!("Could not smooth the measurements: {}", e),
+        }
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "emulate" {
+        // parrot_hat_follow emulate [address], serves until Enter is pressed
+        let address = args.get(2).map(|a| a.as_str()).unwrap_or(LOCAL_ADDRESS);
+        let world = VirtualController::new(20.0, 1, 0.01,
+            MoveSquares::new(0.7, 500),
+            RandomWind::new_polar(3.0, 150, 2000), false,
+        );
+        match DroneEmulator::start(address, world) {
+            Ok(_emulator) => {
+                println!("Press Enter to stop the emulator");
+                let mut line = String::new();
+                let _ = std::io::stdin().read_line(&mut line);
+            }
+            Err(e) => println!("Could not start the emulator on {}: {}", address, e),
+        }
+        return;
+    }
+
+    parrot::safety::install_signal_handler();
+
+    if args.len() == 2 && args[1] == "calibrate" {
+        // parrot_hat_follow calibrate, flies or simulates the controller set in config.controller
+        run_calibration("config.controller");
+        return;
+    }
 
     println!("Starting up the UI");
     let mut iced_settings = Settings::<()>::default();
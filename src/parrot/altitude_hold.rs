@@ -0,0 +1,161 @@
+use std::sync::{Arc, Mutex};
+
+use crate::parrot::drone::{Drone, NavDataValue};
+
+/// The last altitude read from the navdata in centimeters, shared with the ground projection (None while it is unknown).
+pub type AltitudeLevel = Arc<Mutex<Option<i32>>>;
+
+/// Anything that can tell the current altitude in centimeters (the drone, or a simulation in the tests).
+pub trait AltitudeSource {
+    fn get_altitude(&mut self) -> Option<i32>;
+}
+
+impl AltitudeSource for Drone {
+    fn get_altitude(&mut self) -> Option<i32> {
+        match self.get_navdata("demo_altitude") {
+            Some(NavDataValue::Int(a)) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// Gains of the altitude hold, as read from the sixth line of config.controller.
+/// All of them being zero turns the altitude hold off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AltitudeSettings {
+    /// Vertical speed command per centimeter of altitude error
+    pub kp: f64,
+    /// Vertical speed command per centimeter of accumulated error (per command)
+    pub ki: f64,
+    /// Vertical speed command per centimeter of altitude change between two commands
+    pub kd: f64,
+}
+
+impl Default for AltitudeSettings {
+    fn default() -> AltitudeSettings {
+        AltitudeSettings {
+            kp: 0.004,
+            ki: 0.0001,
+            kd: 0.0,
+        }
+    }
+}
+
+impl AltitudeSettings {
+    /// Parses a line of the form "kp ki kd", missing or invalid values are replaced by the defaults.
+    pub fn parse(line: &str) -> AltitudeSettings {
+        let default = AltitudeSettings::default();
+        let args: Vec<&str> = line.split_whitespace().collect::<Vec<&str>>();
+        let value = |i: usize, default: f64| match args.get(i).map(|a| a.parse::<f64>()) {
+            Some(Ok(v)) if v.is_finite() => v,
+            _ => default
+        };
+
+        AltitudeSettings {
+            kp: value(0, default.kp),
+            ki: value(1, default.ki),
+            kd: value(2, default.kd),
+        }
+    }
+
+    /// The line parse reads back.
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", self.kp, self.ki, self.kd)
+    }
+}
+
+/// The correction never asks for more than this vertical speed, the same that is used to climb after takeoff.
+const MAX_CORRECTION: f64 = 0.5;
+
+/// PID controller that keeps the drone at the target altitude by correcting the down_up commands.
+pub struct AltitudeHold {
+    settings: AltitudeSettings,
+    target: i32,
+    integral: f64,
+    last_altitude: Option<i32>,
+}
+
+impl AltitudeHold {
+    pub fn new(target: i32, settings: AltitudeSettings) -> AltitudeHold {
+        AltitudeHold {
+            settings,
+            target,
+            integral: 0.0,
+            last_altitude: None,
+        }
+    }
+
+    /// Forgets the accumulated error, called on takeoff.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_altitude = None;
+    }
+
+    /// The vertical speed to add to the down_up command, positive values move the drone up.
+    /// Nothing is corrected if the altitude is unknown.
+    pub fn correct<S: AltitudeSource + ?Sized>(&mut self, source: &mut S) -> f64 {
+        let AltitudeSettings { kp, ki, kd } = self.settings;
+        if kp == 0.0 && ki == 0.0 && kd == 0.0 {
+            return 0.0;
+        }
+        let altitude = match source.get_altitude() {
+            Some(a) => a,
+            None => return 0.0,
+        };
+        let error = (self.target - altitude) as f64;
+        // The derivative is taken on the altitude, so that changing the target doesn't cause a kick
+        let change = match self.last_altitude {
+            Some(last) => (altitude - last) as f64,
+            None => 0.0,
+        };
+        self.last_altitude = Some(altitude);
+
+        let unsaturated = kp * error + ki * (self.integral + error) - kd * change;
+        // The error is only accumulated while the output isn't saturated (anti-windup)
+        if unsaturated.abs() < MAX_CORRECTION {
+            self.integral += error;
+        }
+        (kp * error + ki * self.integral - kd * change).max(-MAX_CORRECTION).min(MAX_CORRECTION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A drone that climbs 10 cm per command at full vertical speed, and sinks 0.5 cm per command.
+    struct SimulatedAltitude {
+        altitude: f64,
+        known: bool,
+    }
+
+    impl AltitudeSource for SimulatedAltitude {
+        fn get_altitude(&mut self) -> Option<i32> {
+            if self.known {
+                Some(self.altitude as i32)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Starts the simulated drone below the target altitude and checks that the altitude hold
+    /// reaches it and cancels the constant sinking, and that it does nothing without navdata.
+    #[test]
+    fn altitude_hold_test() {
+        let mut hold = AltitudeHold::new(300, AltitudeSettings::default());
+        let mut drone = SimulatedAltitude { altitude: 220.0, known: true };
+        for _i in 0..1000 {
+            let correction = hold.correct(&mut drone);
+            drone.altitude += correction * 10.0 - 0.5;
+        }
+        assert!((drone.altitude - 300.0).abs() < 2.0, "The altitude hold did not reach the target (altitude: {})", drone.altitude);
+
+        drone.known = false;
+        assert_eq!(hold.correct(&mut drone), 0.0, "The altitude hold corrected without knowing the altitude!");
+
+        let mut off = AltitudeHold::new(300, AltitudeSettings { kp: 0.0, ki: 0.0, kd: 0.0 });
+        drone.known = true;
+        assert_eq!(off.correct(&mut drone), 0.0, "The turned off altitude hold corrected!");
+    }
+}
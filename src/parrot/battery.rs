@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The last battery percentage read from the drone, shared with the UI (None while it is unknown).
+pub type BatteryLevel = Arc<Mutex<Option<u32>>>;
+
+/// Minimum time between two battery readings.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Battery percentages at which the user is warned and the drone is landed,
+/// as read from the fifth line of config.controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatterySettings {
+    pub warning_level: u32,
+    pub critical_level: u32,
+}
+
+impl Default for BatterySettings {
+    fn default() -> BatterySettings {
+        BatterySettings {
+            warning_level: 30,
+            critical_level: 15,
+        }
+    }
+}
+
+impl BatterySettings {
+    /// Parses a line of the form "warning_level critical_level", missing or invalid values are
+    /// replaced by the defaults.
+    pub fn parse(line: &str) -> BatterySettings {
+        let default = BatterySettings::default();
+        let args: Vec<&str> = line.split_whitespace().collect::<Vec<&str>>();
+
+        BatterySettings {
+            warning_level: match args.first().map(|a| a.parse::<u32>()) {
+                Some(Ok(l)) => l,
+                _ => default.warning_level
+            },
+            critical_level: match args.get(1).map(|a| a.parse::<u32>()) {
+                Some(Ok(l)) => l,
+                _ => default.critical_level
+            },
+        }
+    }
+
+    /// The line parse reads back.
+    pub fn to_line(&self) -> String {
+        format!("{} {}", self.warning_level, self.critical_level)
+    }
+}
+
+/// What has to be done after a battery reading.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatteryEvent {
+    /// The level crossed the warning level.
+    Warning(u32),
+    /// The level crossed the critical level, the drone has to land.
+    Critical(u32),
+}
+
+/// Keeps track of the battery level, each event is only reported once per flight.
+pub struct BatteryMonitor {
+    settings: BatterySettings,
+    level: BatteryLevel,
+    last_poll: Option<Instant>,
+    warned: bool,
+    critical: bool,
+}
+
+impl BatteryMonitor {
+    pub fn new(settings: BatterySettings) -> BatteryMonitor {
+        BatteryMonitor {
+            settings,
+            level: Arc::new(Mutex::new(None)),
+            last_poll: None,
+            warned: false,
+            critical: false,
+        }
+    }
+
+    /// A handle to the last reading, that can be read from other threads.
+    pub fn get_level(&self) -> BatteryLevel {
+        self.level.clone()
+    }
+
+    /// Whether enough time passed since the last reading to read the battery again.
+    pub fn should_poll(&self) -> bool {
+        match self.last_poll {
+            Some(t) => t.elapsed() >= POLL_INTERVAL,
+            None => true,
+        }
+    }
+
+    /// Stores a new reading and returns the event it caused, if any.
+    pub fn update(&mut self, percentage: u32) -> Option<BatteryEvent> {
+        self.last_poll = Some(Instant::now());
+        if let Ok(mut level) = self.level.lock() {
+            *level = Some(percentage);
+        }
+
+        if percentage <= self.settings.critical_level && !self.critical {
+            self.critical = true;
+            self.warned = true;
+            Some(BatteryEvent::Critical(percentage))
+        } else if percentage <= self.settings.warning_level && !self.warned {
+            self.warned = true;
+            Some(BatteryEvent::Warning(percentage))
+        } else {
+            None
+        }
+    }
+
+    /// Called when the drone takes off again, so that the events are reported for the new flight.
+    pub fn reset(&mut self) {
+        self.warned = false;
+        self.critical = false;
+    }
+}
@@ -0,0 +1,153 @@
+/// The cameras of the AR.Drone, only one of them can stream at a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Camera {
+    Front,
+    Ground,
+}
+
+/// The ground projection never aims further out than this angle from straight down (75 degrees),
+/// so that a camera looking at the horizon still has a point on the ground to center the hat on.
+const MAX_AIM_ANGLE: f64 = 1.309;
+
+/// The chosen camera and its geometry, as read from the ninth line of config.controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraModel {
+    pub camera: Camera,
+    /// Horizontal field of view in radians
+    pub horizontal_fov: f64,
+    /// Angle of the optical axis from straight down in radians, towards the front of the drone
+    pub mounting_angle: f64,
+}
+
+impl Default for CameraModel {
+    fn default() -> CameraModel {
+        CameraModel::ground()
+    }
+}
+
+impl CameraModel {
+    /// The 64 degree ground camera of the AR.Drone 2.0, looking straight down.
+    pub fn ground() -> CameraModel {
+        CameraModel {
+            camera: Camera::Ground,
+            horizontal_fov: 64f64.to_radians(),
+            mounting_angle: 0.0,
+        }
+    }
+
+    /// The 92 degree front camera of the AR.Drone 2.0, looking forward.
+    pub fn front() -> CameraModel {
+        CameraModel {
+            camera: Camera::Front,
+            horizontal_fov: 92f64.to_radians(),
+            mounting_angle: 90f64.to_radians(),
+        }
+    }
+
+    /// Parses a line of the form "Ground|Front [horizontal_fov mounting_angle]" with the angles in degrees,
+    /// missing or invalid values are replaced by the ones of the chosen camera.
+    pub fn parse(line: &str) -> CameraModel {
+        let args: Vec<&str> = line.split_whitespace().collect::<Vec<&str>>();
+        let default = match args.first() {
+            Some(&"Front") => CameraModel::front(),
+            _ => CameraModel::ground(),
+        };
+        let angle = |i: usize, max: f64, default: f64| match args.get(i).map(|a| a.parse::<f64>()) {
+            Some(Ok(v)) if v >= 0.0 && v < max => v.to_radians(),
+            _ => default
+        };
+
+        CameraModel {
+            camera: default.camera,
+            horizontal_fov: angle(1, 180.0, default.horizontal_fov),
+            mounting_angle: angle(2, 180.0, default.mounting_angle),
+        }
+    }
+
+    /// The line parse reads back.
+    pub fn to_line(&self) -> String {
+        let name = match self.camera {
+            Camera::Front => "Front",
+            Camera::Ground => "Ground",
+        };
+        // Rounded, so that the degrees are written back the way they were given
+        let degrees = |a: f64| (a.to_degrees() * 100.0).round() / 100.0;
+        format!("{} {} {}", name, degrees(self.horizontal_fov), degrees(self.mounting_angle))
+    }
+
+    /// Distance of the image plane in pixels for a video of the given width.
+    fn focal_length(&self, width: f64) -> f64 {
+        width / 2.0 / (self.horizontal_fov / 2.0).tan()
+    }
+
+    /// Pixels per centimeter on the ground at the given height, for a camera looking straight down.
+    pub fn pixels_per_cm(&self, width: f64, height: f64) -> f64 {
+        self.focal_length(width) / height
+    }
+
+    /// The point on the ground (right, forward in centimeters from the point below the drone) seen at the
+    /// given offset from the center of the image (right, up in pixels), None above the horizon.
+    pub fn pixel_to_ground(&self, (u, v): (f64, f64), width: f64, height: f64) -> Option<(f64, f64)> {
+        let f = self.focal_length(width);
+        let (sin, cos) = (self.mounting_angle.sin(), self.mounting_angle.cos());
+        // The ray through the pixel has to point downwards to reach the ground
+        let down = f * cos - v * sin;
+        if down <= 1e-9 {
+            return None;
+        }
+        let t = height / down;
+        Some((u * t, (f * sin + v * cos) * t))
+    }
+
+    /// The offset from the center of the image (right, up in pixels) at which the given point on the ground
+    /// (right, forward in centimeters from the point below the drone) is seen, None behind the camera.
+    pub fn ground_to_pixel(&self, (x, y): (f64, f64), width: f64, height: f64) -> Option<(f64, f64)> {
+        let f = self.focal_length(width);
+        let (sin, cos) = (self.mounting_angle.sin(), self.mounting_angle.cos());
+        // Distance of the point along the optical axis
+        let depth = y * sin + height * cos;
+        if depth <= 1e-9 {
+            return None;
+        }
+        Some((f * x / depth, f * (y * cos - height * sin) / depth))
+    }
+
+    /// How far ahead the hat is kept: the point on the ground in the center of the image,
+    /// for cameras looking at the horizon the point at MAX_AIM_ANGLE.
+    pub fn aim_distance(&self, height: f64) -> f64 {
+        height * self.mounting_angle.min(MAX_AIM_ANGLE).tan()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ground camera sees the same distance in every pixel and aims below the drone.
+    #[test]
+    fn ground_camera_test() {
+        let width = 640.0;
+        let ground = CameraModel::ground();
+        let scale = ground.pixels_per_cm(width, 300.0);
+        let (x, y) = ground.pixel_to_ground((100.0, -50.0), width, 300.0).unwrap();
+        assert!((x * scale - 100.0).abs() < 1e-6 && (y * scale + 50.0).abs() < 1e-6, "The ground camera is not linear!");
+        assert_eq!(ground.aim_distance(300.0), 0.0, "The ground camera doesn't aim below the drone!");
+    }
+
+    /// A camera tilted by 45 degrees sees the ground at the flight height in front of it in the center of the image,
+    /// nothing is seen above the horizon, and the config line is read back the way it was written.
+    #[test]
+    fn tilted_camera_test() {
+        let width = 640.0;
+        let tilted = CameraModel::parse("Front 92 45");
+        let (x, y) = tilted.pixel_to_ground((0.0, 0.0), width, 300.0).unwrap();
+        assert!(x.abs() < 1e-6 && (y - 300.0).abs() < 1e-6, "The tilted camera aims at ({}, {})", x, y);
+        assert!((tilted.aim_distance(300.0) - 300.0).abs() < 1e-6, "The tilted camera keeps the hat at the wrong distance!");
+        assert!(tilted.pixel_to_ground((0.0, 400.0), width, 300.0).is_none(), "A pixel above the horizon was projected!");
+        let (u, v) = tilted.ground_to_pixel((x + 50.0, y - 80.0), width, 300.0).unwrap();
+        let (back_x, back_y) = tilted.pixel_to_ground((u, v), width, 300.0).unwrap();
+        assert!((back_x - x - 50.0).abs() < 1e-6 && (back_y - y + 80.0).abs() < 1e-6, "The ground point was not projected back!");
+        assert_eq!(CameraModel::parse(&tilted.to_line()), tilted, "The camera line was not read back!");
+        assert_eq!(CameraModel::parse(""), CameraModel::default(), "The default camera is not the ground camera!");
+    }
+}
@@ -0,0 +1,66 @@
+/// Address the AR.Drone uses on its own WiFi network.
+pub const DEFAULT_ADDRESS: &str = "192.168.1.1";
+/// TCP port on which the AR.Drone streams its video.
+pub const DEFAULT_VIDEO_PORT: u16 = 5555;
+/// Size of the video of the AR.Drone 2.0, used when the stream doesn't tell its own.
+pub const DEFAULT_VIDEO_WIDTH: usize = 640;
+pub const DEFAULT_VIDEO_HEIGHT: usize = 360;
+
+/// Where the drone and its video stream can be reached, as read from the fourth line of config.controller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParrotConnection {
+    /// Address of the drone
+    pub address: String,
+    /// Port of the video stream on the drone
+    pub video_port: u16,
+    /// Full URL of the video stream, used instead of the address and port if the stream
+    /// is forwarded through a bridge or a proxy
+    pub video_url: Option<String>,
+}
+
+impl Default for ParrotConnection {
+    fn default() -> ParrotConnection {
+        ParrotConnection {
+            address: String::from(DEFAULT_ADDRESS),
+            video_port: DEFAULT_VIDEO_PORT,
+            video_url: None,
+        }
+    }
+}
+
+impl ParrotConnection {
+    /// Parses a line of the form "address video_port [video_url]", missing or invalid values are
+    /// replaced by the defaults.
+    pub fn parse(line: &str) -> ParrotConnection {
+        let default = ParrotConnection::default();
+        let args: Vec<&str> = line.split_whitespace().collect::<Vec<&str>>();
+
+        ParrotConnection {
+            address: match args.first() {
+                Some(a) => String::from(*a),
+                None => default.address
+            },
+            video_port: match args.get(1).map(|p| p.parse::<u16>()) {
+                Some(Ok(p)) => p,
+                _ => default.video_port
+            },
+            video_url: args.get(2).map(|u| String::from(*u)),
+        }
+    }
+
+    /// The line parse reads back.
+    pub fn to_line(&self) -> String {
+        match &self.video_url {
+            Some(url) => format!("{} {} {}", self.address, self.video_port, url),
+            None => format!("{} {}", self.address, self.video_port),
+        }
+    }
+
+    /// URL of the video stream that can be opened with a VideoCapture.
+    pub fn video_url(&self) -> String {
+        match &self.video_url {
+            Some(url) => url.clone(),
+            None => format!("tcp://{}:{}", self.address, self.video_port),
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Ports of the AR.Drone 2.0
+pub const NAVDATA_PORT: u16 = 5554;
+pub const AT_PORT: u16 = 5556;
+
+/// The state commands (AT*REF and AT*PCMD) are repeated this often, the drone hovers if they stop.
+const COMMAND_INTERVAL: Duration = Duration::from_millis(30);
+/// The navdata is asked for again if no packet arrived for this long.
+const NAVDATA_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time startup waits for the first navdata packet.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// The emergency bit is sent for this many command intervals, sending it for longer would reset the emergency.
+const EMERGENCY_REPEATS: usize = 3;
+
+/// Bits of AT*REF: 18, 20, 22, 24 and 28 are always set, 9 is takeoff, 8 is emergency.
+const REF_BASE: u32 = 0x1154_0000;
+const REF_TAKEOFF: u32 = 1 << 9;
+const REF_EMERGENCY: u32 = 1 << 8;
+
+const NAVDATA_HEADER: u32 = 0x5566_7788;
+const NAVDATA_DEMO_TAG: u16 = 0;
+
+/// A value read from the navdata of the drone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NavDataValue {
+    Int(i32),
+    Uint(u32),
+    Float(f32),
+}
+
+/// What the drone is told on every command interval.
+struct Control {
+    socket: UdpSocket,
+    sequence: u32,
+    reference: u32,
+    /// (left_right, back_front, down_up, turn_left_right), None to hover
+    movement: Option<(f32, f32, f32, f32)>,
+    emergency_repeats: usize,
+}
+
+impl Control {
+    /// The given commands in one packet, each with the next sequence number.
+    fn packet(&mut self, commands: &[String]) -> String {
+        let mut packet = String::new();
+        for command in commands {
+            self.sequence += 1;
+            packet.push_str(&command.replacen("{}", &self.sequence.to_string(), 1));
+            packet.push('\r');
+        }
+        packet
+    }
+
+    fn send(&mut self, commands: &[String]) {
+        let packet = self.packet(commands);
+        let _ = self.socket.send(packet.as_bytes());
+    }
+
+    /// The AT*REF and AT*PCMD commands that are repeated on every command interval.
+    fn state_commands(&mut self) -> Vec<String> {
+        let reference = if self.emergency_repeats > 0 {
+            self.emergency_repeats -= 1;
+            self.reference | REF_EMERGENCY
+        } else {
+            self.reference
+        };
+        let movement = match self.movement {
+            // Floats are sent as the integer with the same bits
+            Some((l, b, d, t)) => format!("AT*PCMD={{}},1,{},{},{},{}",
+                                          l.to_bits() as i32, (-b).to_bits() as i32, d.to_bits() as i32, t.to_bits() as i32),
+            None => String::from("AT*PCMD={},0,0,0,0,0"),
+        };
+        vec![format!("AT*REF={{}},{}", reference), movement]
+    }
+
+    fn send_state(&mut self) {
+        let commands = self.state_commands();
+        self.send(&commands);
+    }
+
+    fn land(&mut self) {
+        self.reference = REF_BASE;
+        self.movement = None;
+    }
+
+    fn emergency(&mut self) {
+        self.land();
+        self.emergency_repeats = EMERGENCY_REPEATS;
+    }
+}
+
+/// Changes the state of the drone and sends it right away, instead of waiting for the next repetition.
+fn set_state<F: FnOnce(&mut Control)>(control: &Mutex<Control>, change: F) {
+    if let Ok(mut control) = control.lock() {
+        change(&mut control);
+        control.send_state();
+    }
+}
+
+/// Lands a Drone from another thread (e.g. the signal handler), as long as the Drone still exists.
+#[derive(Clone)]
+pub struct DroneHandle {
+    control: Weak<Mutex<Control>>,
+}
+
+impl DroneHandle {
+    pub fn land(&self) {
+        if let Some(control) = self.control.upgrade() {
+            set_state(&control, Control::land);
+        }
+    }
+
+    /// Cuts the motors, see Drone::emergency.
+    pub fn emergency(&self) {
+        if let Some(control) = self.control.upgrade() {
+            set_state(&control, Control::emergency);
+        }
+    }
+}
+
+/// Connection to an AR.Drone 2.0 at any address: AT commands on UDP 5556, navdata on UDP 5554.
+/// The takeoff / landing state and the last movement are repeated by a background thread, like the
+/// official SDK does, and the demo navdata is read by another one.
+pub struct Drone {
+    address: String,
+    control: Arc<Mutex<Control>>,
+    navdata: Arc<Mutex<HashMap<&'static str, NavDataValue>>>,
+    last_navdata: Arc<Mutex<Option<Instant>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Drone {
+    pub fn connect(address: &str) -> io::Result<Drone> {
+        let at_socket = UdpSocket::bind("0.0.0.0:0")?;
+        at_socket.connect((address, AT_PORT))?;
+        // The navdata port of the drone is used locally too if it is free (it isn't if an emulator runs here)
+        let navdata_socket = UdpSocket::bind(("0.0.0.0", NAVDATA_PORT)).or_else(|_| UdpSocket::bind("0.0.0.0:0"))?;
+        navdata_socket.connect((address, NAVDATA_PORT))?;
+        navdata_socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+        let control = Arc::new(Mutex::new(Control {
+            socket: at_socket,
+            sequence: 0,
+            reference: REF_BASE,
+            movement: None,
+            emergency_repeats: 0,
+        }));
+        let navdata = Arc::new(Mutex::new(HashMap::new()));
+        let last_navdata = Arc::new(Mutex::new(None));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let (c, r) = (control.clone(), running.clone());
+        thread::spawn(move || Drone::repeat_state(c, r));
+        let (n, l, r) = (navdata.clone(), last_navdata.clone(), running.clone());
+        thread::spawn(move || Drone::read_navdata(navdata_socket, n, l, r));
+
+        Ok(Drone {
+            address: String::from(address),
+            control,
+            navdata,
+            last_navdata,
+            running,
+        })
+    }
+
+    fn repeat_state(control: Arc<Mutex<Control>>, running: Arc<AtomicBool>) {
+        while running.load(Ordering::SeqCst) {
+            match control.lock() {
+                Ok(mut control) => control.send_state(),
+                Err(_) => break,
+            }
+            thread::sleep(COMMAND_INTERVAL);
+        }
+    }
+
+    fn read_navdata(socket: UdpSocket, navdata: Arc<Mutex<HashMap<&'static str, NavDataValue>>>,
+                    last_navdata: Arc<Mutex<Option<Instant>>>, running: Arc<AtomicBool>) {
+        let mut buffer = [0u8; 4096];
+        let mut last_request: Option<Instant> = None;
+        let mut last_packet: Option<Instant> = None;
+        while running.load(Ordering::SeqCst) {
+            // Any packet sent to the navdata port starts the stream
+            let silent = last_packet.map(|t| t.elapsed() >= NAVDATA_TIMEOUT).unwrap_or(true);
+            if silent && last_request.map(|t| t.elapsed() >= NAVDATA_TIMEOUT).unwrap_or(true) {
+                let _ = socket.send(&[1, 0, 0, 0]);
+                last_request = Some(Instant::now());
+            }
+            let size = match socket.recv(&mut buffer) {
+                Ok(size) => size,
+                Err(_) => continue,
+            };
+            if let Some(values) = parse_navdata(&buffer[..size]) {
+                last_packet = Some(Instant::now());
+                if let Ok(mut last) = last_navdata.lock() {
+                    *last = last_packet;
+                }
+                if let Ok(mut navdata) = navdata.lock() {
+                    navdata.extend(values);
+                }
+            }
+        }
+    }
+
+    fn send(&self, commands: &[String]) {
+        if let Ok(mut control) = self.control.lock() {
+            control.send(commands);
+        }
+    }
+
+    fn set_state<F: FnOnce(&mut Control)>(&self, change: F) {
+        set_state(&self.control, change);
+    }
+
+    pub fn handle(&self) -> DroneHandle {
+        DroneHandle { control: Arc::downgrade(&self.control) }
+    }
+
+    /// Switches the navdata to the demo mode and waits for it to arrive.
+    pub fn startup(&mut self) -> Result<(), String> {
+        let start = Instant::now();
+        while start.elapsed() < STARTUP_TIMEOUT {
+            self.send(&[String::from("AT*CONFIG={},\"general:navdata_demo\",\"TRUE\"")]);
+            thread::sleep(Duration::from_millis(200));
+            if let Ok(Some(_)) = self.last_navdata.lock().map(|l| *l) {
+                return Ok(());
+            }
+        }
+        Err(format!("no navdata arrived from {} in {} seconds", self.address, STARTUP_TIMEOUT.as_secs()))
+    }
+
+    /// Tells the drone that it stands on flat ground, has to be done before takeoff.
+    pub fn trim(&mut self) {
+        self.send(&[String::from("AT*FTRIM={},")]);
+    }
+
+    pub fn use_front_cam(&mut self) {
+        self.send(&[String::from("AT*CONFIG={},\"video:video_channel\",\"0\"")]);
+    }
+
+    pub fn use_ground_cam(&mut self) {
+        self.send(&[String::from("AT*CONFIG={},\"video:video_channel\",\"1\"")]);
+    }
+
+    pub fn takeoff(&mut self) {
+        self.set_state(|c| {
+            c.reference = REF_BASE | REF_TAKEOFF;
+            c.movement = None;
+        });
+    }
+
+    pub fn land(&mut self) {
+        self.set_state(Control::land);
+    }
+
+    /// Cuts the motors immediately, the drone falls from where it is.
+    pub fn emergency(&mut self) {
+        self.set_state(Control::emergency);
+    }
+
+    /// Moves with the given speeds, each between -1.0 and 1.0, until the next command.
+    pub fn mov(&mut self, left_right: f32, back_front: f32, down_up: f32, turn_left_right: f32) {
+        self.set_state(|c| c.movement = Some((left_right, back_front, down_up, turn_left_right)));
+    }
+
+    pub fn mov_up(&mut self, speed: f32) {
+        self.mov(0.0, 0.0, speed, 0.0);
+    }
+
+    pub fn mov_down(&mut self, speed: f32) {
+        self.mov(0.0, 0.0, -speed, 0.0);
+    }
+
+    /// Hovers in place.
+    pub fn stop(&mut self) {
+        self.set_state(|c| c.movement = None);
+    }
+
+    /// The last value of a demo navdata field (demo_battery, demo_altitude, demo_theta, demo_phi, demo_psi,
+    /// demo_vx, demo_vy, demo_vz, demo_ctrl_state), None before the first packet.
+    pub fn get_navdata(&self, field: &str) -> Option<NavDataValue> {
+        self.navdata.lock().ok().and_then(|navdata| navdata.get(field).copied())
+    }
+}
+
+impl Drop for Drone {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Reads the demo option of a navdata packet, None if the packet is not navdata.
+fn parse_navdata(packet: &[u8]) -> Option<Vec<(&'static str, NavDataValue)>> {
+    let u16_at = |i: usize| packet.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |i: usize| packet.get(i..i + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    if u32_at(0)? != NAVDATA_HEADER {
+        return None;
+    }
+
+    // The options follow the 16 byte header: tag, size (with the 4 bytes of the tag and the size), data
+    let mut offset = 16;
+    while let (Some(tag), Some(size)) = (u16_at(offset), u16_at(offset + 2)) {
+        if tag == NAVDATA_DEMO_TAG {
+            let d = offset + 4;
+            let float = |i: usize| u32_at(d + i).map(|v| NavDataValue::Float(f32::from_bits(v)));
+            let values = vec![
+                ("demo_ctrl_state", u32_at(d).map(|v| NavDataValue::Uint(v >> 16))),
+                ("demo_battery", u32_at(d + 4).map(NavDataValue::Uint)),
+                ("demo_theta", float(8)),
+                ("demo_phi", float(12)),
+                ("demo_psi", float(16)),
+                ("demo_altitude", u32_at(d + 20).map(|v| NavDataValue::Int(v as i32))),
+                ("demo_vx", float(24)),
+                ("demo_vy", float(28)),
+                ("demo_vz", float(32)),
+            ];
+            return Some(values.into_iter().filter_map(|(name, value)| value.map(|v| (name, v))).collect());
+        }
+        if size < 4 {
+            break;
+        }
+        offset += size as usize;
+    }
+    Some(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::drone_emulator::EmulatorState;
+
+    fn control() -> Control {
+        Control {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            sequence: 0,
+            reference: REF_BASE,
+            movement: None,
+            emergency_repeats: 0,
+        }
+    }
+
+    /// Sends the state commands of the control to the emulated drone.
+    fn execute_state(control: &mut Control, emulator: &mut EmulatorState) {
+        let commands = control.state_commands();
+        let packet = control.packet(&commands);
+        for command in packet.split('\r').filter(|c| !c.is_empty()) {
+            emulator.execute(command);
+        }
+    }
+
+    #[test]
+    fn sequence_test() {
+        let mut control = control();
+        let commands = control.state_commands();
+        assert_eq!(control.packet(&commands), format!("AT*REF=1,{}\rAT*PCMD=2,0,0,0,0,0\r", REF_BASE));
+        let commands = control.state_commands();
+        assert!(control.packet(&commands).starts_with("AT*REF=3,"), "The sequence number was not increased!");
+    }
+
+    /// Takes off, moves, lands and cuts the motors of the emulated drone through the AT commands.
+    #[test]
+    fn at_commands_test() {
+        let mut control = control();
+        let mut emulator = EmulatorState::new();
+
+        control.reference = REF_BASE | REF_TAKEOFF;
+        execute_state(&mut control, &mut emulator);
+        assert!(emulator.flying && emulator.takeoffs == 1, "The takeoff was not understood!");
+
+        // Negative values are sent as negative integers
+        control.movement = Some((0.1, -0.2, -0.3, 0.4));
+        execute_state(&mut control, &mut emulator);
+        assert_eq!(emulator.command, (0.1f32 as f64, -0.2f32 as f64, -0.3f32 as f64, 0.4f32 as f64));
+        control.movement = None;
+        execute_state(&mut control, &mut emulator);
+        assert_eq!(emulator.command, (0.0, 0.0, 0.0, 0.0), "Hovering was not understood!");
+
+        control.land();
+        execute_state(&mut control, &mut emulator);
+        assert!(!emulator.flying && emulator.landings == 1, "The landing was not understood!");
+
+        control.reference = REF_BASE | REF_TAKEOFF;
+        execute_state(&mut control, &mut emulator);
+        control.emergency();
+        for _i in 0..10 {
+            execute_state(&mut control, &mut emulator);
+        }
+        assert!(!emulator.flying, "The motors were not cut!");
+        assert_eq!(emulator.emergencies, EMERGENCY_REPEATS, "The emergency bit was not sent for a limited time!");
+    }
+
+    #[test]
+    fn parse_navdata_test() {
+        let mut emulator = EmulatorState::new();
+        emulator.flying = true;
+        emulator.altitude = 150.0;
+        emulator.battery = 87.0;
+        emulator.command = (0.5, 0.0, 0.0, 0.0);
+
+        let values: HashMap<&str, NavDataValue> = parse_navdata(&emulator.navdata_packet(7))
+            .expect("The navdata was not recognized!")
+            .into_iter()
+            .collect();
+        assert_eq!(values.get("demo_battery"), Some(&NavDataValue::Uint(87)));
+        assert_eq!(values.get("demo_altitude"), Some(&NavDataValue::Int(150)));
+        assert_eq!(values.get("demo_ctrl_state"), Some(&NavDataValue::Uint(3)));
+        assert_eq!(values.get("demo_phi"), Some(&NavDataValue::Float(6000.0)));
+        assert_eq!(values.get("demo_vy"), Some(&NavDataValue::Float(500.0)));
+
+        assert!(parse_navdata(&[1, 0, 0, 0]).is_none(), "A packet without the navdata header was parsed!");
+        assert_eq!(parse_navdata(&emulator.navdata_packet(8)[..16]), Some(Vec::new()));
+    }
+}
@@ -1 +1,10 @@
-pub mod parrot_controller;
\ No newline at end of file
+pub mod parrot_controller;
+pub mod drone;
+pub mod connection;
+pub mod parrot_error;
+pub mod battery;
+pub mod safety;
+pub mod altitude_hold;
+pub mod telemetry;
+pub mod video_stream;
+pub mod camera;
@@ -1,73 +1,239 @@
 use std::thread;
-use std::time::Duration;
-use std::mem;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
 
-use opencv::videoio::{VideoCapture, CAP_ANY, VideoCaptureTrait};
 use opencv::core::Mat;
 
 use rust_drone_follow::traits::Controller;
 use rust_drone_follow::utils::TextExporter;
 
-use parrot_ar_drone::{Drone, NavDataValue};
+use crate::parrot::drone::{Drone, NavDataValue};
+
+use crate::parrot::connection::ParrotConnection;
+use crate::parrot::parrot_error::ParrotError;
+use crate::parrot::battery::{BatteryMonitor, BatterySettings, BatteryLevel, BatteryEvent};
+use crate::parrot::safety::{self, LANDING_TIME};
+use crate::parrot::altitude_hold::{AltitudeHold, AltitudeSettings, AltitudeSource, AltitudeLevel};
+use crate::parrot::telemetry::TelemetryRecorder;
+use crate::parrot::video_stream::VideoStream;
+use crate::parrot::camera::{Camera, CameraModel};
+use crate::utils::calibration::{ControllerGains, StepResponse};
+use crate::kalman_filter::{normalize_angle, FrameTimestamp};
+
+/// If no new frame arrives for this long, the drone hovers until the video recovers...
+const FRAME_TIMEOUT: Duration = Duration::from_millis(500);
+/// ...but if it doesn't recover for this long, the follower is stopped and the drone lands.
+const VIDEO_LOST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Commands of the flown calibration steps, small enough to stay within a few meters.
+const CALIBRATION_MOVE: f64 = 0.1;
+const CALIBRATION_TURN: f64 = 0.3;
+/// The drone hovers for HOVER_TIME before each step, which lasts for STEP_TIME.
+const HOVER_TIME: Duration = Duration::from_secs(1);
+const STEP_TIME: Duration = Duration::from_secs(2);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
 
 pub struct ParrotController {
     print_debug: bool,
     flight_height: i32,
-    video: VideoCapture,
+    video: VideoStream,
+    video_stalled: bool,
+    timestamps: FrameTimestamp,
     drone: Option<Drone>,
     te: TextExporter,
+    initialized: bool,
+    /// The id with which the flight is registered for the emergency landing, None on the ground
+    flight: Option<usize>,
+    battery: BatteryMonitor,
+    stop_sender: Option<Sender<i32>>,
+    altitude_hold: AltitudeHold,
+    altitude: AltitudeLevel,
+    telemetry: Option<TelemetryRecorder>,
+    gains: ControllerGains,
+    camera: CameraModel,
 }
 
 impl ParrotController {
-    pub fn new(flight_height: i32, debug: bool) -> ParrotController {
-        ParrotController {
+    pub fn new(flight_height: i32, debug: bool, connection: &ParrotConnection, battery: BatterySettings,
+               altitude: AltitudeSettings) -> Result<ParrotController, ParrotError> {
+        let drone = Drone::connect(connection.address.as_str())
+            .map_err(|e| ParrotError::ConnectionFailed(format!("{}: {}", connection.address, e)))?;
+        let video = VideoStream::open(connection.video_url().as_str())?;
+        if debug {
+            println!("Video: {}x{}", video.get_width(), video.get_height());
+        }
+        Ok(ParrotController {
             flight_height,
             print_debug: debug,
-            video: VideoCapture::from_file("tcp://192.168.1.1:5555", CAP_ANY).unwrap(),
-            drone: Some(Drone::new()),
+            video,
+            video_stalled: false,
+            timestamps: Arc::new(Mutex::new(None)),
+            drone: Some(drone),
             te: TextExporter::new(),
+            initialized: false,
+            flight: None,
+            battery: BatteryMonitor::new(battery),
+            stop_sender: None,
+            altitude_hold: AltitudeHold::new(flight_height, altitude),
+            altitude: Arc::new(Mutex::new(None)),
+            telemetry: None,
+            gains: ControllerGains::PARROT,
+            camera: CameraModel::default(),
+        })
+    }
+
+    /// Follows with the given gains instead of the uncalibrated ones.
+    pub fn with_gains(mut self, gains: ControllerGains) -> ParrotController {
+        self.gains = gains;
+        self
+    }
+
+    pub fn get_gains(&self) -> ControllerGains {
+        self.gains
+    }
+
+    /// Streams the video of the given camera instead of the ground camera.
+    pub fn with_camera(mut self, camera: CameraModel) -> ParrotController {
+        self.camera = camera;
+        self
+    }
+
+    pub fn get_camera(&self) -> CameraModel {
+        self.camera
+    }
+
+    pub fn get_flight_height(&self) -> i32 {
+        self.flight_height
+    }
+
+    /// The altitude of the drone, updated on every frame, to be given to the ground projection.
+    pub fn get_altitude_level(&self) -> AltitudeLevel {
+        self.altitude.clone()
+    }
+
+    /// The time of the current frame in the video stream, to be given to the filter.
+    pub fn get_frame_timestamps(&self) -> FrameTimestamp {
+        self.timestamps.clone()
+    }
+
+    /// Records the navdata of the drone on every frame to the given CSV file.
+    pub fn record_telemetry(&mut self, filename: &str) {
+        match TelemetryRecorder::new(filename) {
+            Ok(recorder) => self.telemetry = Some(recorder),
+            Err(e) => println!("Could not record the navdata to {}: {}", filename, e),
         }
     }
 
-    pub fn get_current_flight_height(&mut self, drone: &mut Drone) -> i32 {
-        if let Some(r) = drone.get_navdata("demo_altitude") {
-            if let NavDataValue::Int(a) = r {
-                return a;
-            }
+    /// Sets the channel with which the HatFollower running this controller can be stopped.
+    /// On critical battery the follower is stopped through it, so that it lands the drone
+    /// the same way as when the user stops it.
+    pub fn set_stop_sender(&mut self, sender: Sender<i32>) {
+        self.stop_sender = Some(sender);
+    }
+
+    /// The last battery reading, updated while the drone is flying.
+    pub fn get_battery_level(&self) -> BatteryLevel {
+        self.battery.get_level()
+    }
+
+    fn read_battery(drone: &mut Drone) -> Option<u32> {
+        match drone.get_navdata("demo_battery") {
+            Some(NavDataValue::Uint(a)) => Some(a),
+            _ => None,
         }
-        0
     }
-}
 
-impl Controller for ParrotController {
-    fn init(&mut self) {
-        let mut drone = self.drone.take().unwrap();
-        match drone.startup() {
-            Ok(()) => {
-                thread::sleep(Duration::from_secs(2));
-                drone.trim();
-                thread::sleep(Duration::from_secs(2));
-                drone.use_ground_cam();
-                match drone.get_navdata("demo_battery") {
-                    Some(NavDataValue::Uint(a)) => { println!("Battery: {}%", a); }
-                    _ => { println!("Battery status unknown!"); }
-                }
-                thread::sleep(Duration::from_secs(2));
+    /// Reads the battery at most once in every second, and lands the drone if it is critical.
+    /// Returns whether the drone has to land.
+    fn poll_battery(&mut self) -> bool {
+        if self.flight.is_none() || !self.battery.should_poll() {
+            return false;
+        }
+        let percentage = match self.drone.as_mut().and_then(ParrotController::read_battery) {
+            Some(p) => p,
+            None => return false,
+        };
+        if self.print_debug {
+            println!("Battery: {}%", percentage);
+        }
+        match self.battery.update(percentage) {
+            Some(BatteryEvent::Warning(p)) => {
+                println!("Battery low: {}%", p);
             }
-            Err(s) => {
-                panic!("Drone startup failed! Err: {}", s);
+            Some(BatteryEvent::Critical(p)) => {
+                println!("Battery critical: {}%, landing!", p);
+                self.stop_follower();
+                return true;
             }
+            None => {}
         }
-        self.drone.replace(drone);
+        false
     }
 
-    fn shutdown(&mut self) {
-        self.drone.take().unwrap();
+    /// Stops the follower, so that it lands the drone the same way as when the user stops it.
+    /// Without a follower listening the drone is landed directly.
+    fn stop_follower(&mut self) {
+        let stopped = match &self.stop_sender {
+            Some(sx) => sx.send(0).is_ok(),
+            None => false,
+        };
+        if !stopped {
+            self.land();
+        }
     }
 
-    fn takeoff(&mut self) {
-        let mut drone = self.drone.take().unwrap();
+    pub fn get_current_flight_height(&mut self, drone: &mut Drone) -> i32 {
+        drone.get_altitude().unwrap_or(0)
+    }
+
+    /// Starts up the drone and switches to the chosen camera. Calling it again after
+    /// it succeeded does nothing, so it can be done before handing the controller to the HatFollower.
+    pub fn try_init(&mut self) -> Result<(), ParrotError> {
+        if self.initialized {
+            return Ok(());
+        }
+        let drone = self.drone.as_mut().ok_or(ParrotError::NotConnected)?;
+        drone.startup().map_err(|s| ParrotError::StartupFailed(s.to_string()))?;
+        thread::sleep(Duration::from_secs(2));
+        drone.trim();
+        thread::sleep(Duration::from_secs(2));
+        match self.camera.camera {
+            Camera::Front => drone.use_front_cam(),
+            Camera::Ground => drone.use_ground_cam(),
+        }
+        match ParrotController::read_battery(drone) {
+            Some(a) => {
+                println!("Battery: {}%", a);
+                self.battery.update(a);
+            }
+            _ => { println!("Battery status unknown!"); }
+        }
+        thread::sleep(Duration::from_secs(2));
+        self.initialized = true;
+        Ok(())
+    }
+
+    /// Takes off and climbs to flight_height. If the height is not reached in 10 seconds
+    /// the drone is landed before the error is returned.
+    pub fn try_takeoff(&mut self) -> Result<(), ParrotError> {
+        if self.flight.is_some() {
+            return Ok(());
+        }
+        if !self.initialized {
+            return Err(ParrotError::NotInitialized);
+        }
+        self.battery.reset();
+        if let Some(BatteryEvent::Critical(level)) = self.drone.as_mut()
+            .and_then(ParrotController::read_battery)
+            .and_then(|level| self.battery.update(level)) {
+            return Err(ParrotError::BatteryLow(level));
+        }
+        let mut drone = self.drone.take().ok_or(ParrotError::NotConnected)?;
+        safety::clear_emergency();
+        self.altitude_hold.reset();
         drone.takeoff();
+        // From here on the drone has to be landed if anything goes wrong
+        self.flight = Some(safety::took_off(drone.handle()));
         thread::sleep(Duration::from_secs(3));
         if self.print_debug {
             println!("Move UP!");
@@ -78,12 +244,18 @@ impl Controller for ParrotController {
             drone.mov_up(0.5);
             thread::sleep(Duration::from_millis(200));
             current_height = self.get_current_flight_height(&mut drone);
-            if i == 50 {
-                println!("{} cm was not reached within 10 seconds! (currently: {}) Stopping!", self.flight_height, current_height);
+            if i == 50 || safety::emergency_requested() {
                 drone.land();
-                thread::sleep(Duration::from_secs(10));
-                mem::drop(drone);
-                panic!("Height was not reached in time!");
+                if let Some(flight) = self.flight.take() {
+                    safety::landed(flight);
+                }
+                thread::sleep(LANDING_TIME);
+                self.drone.replace(drone);
+                return Err(if i == 50 {
+                    ParrotError::HeightNotReached(self.flight_height, current_height)
+                } else {
+                    ParrotError::EmergencyLanding
+                });
             }
             i += 1;
         }
@@ -93,12 +265,118 @@ impl Controller for ParrotController {
         drone.stop();
         drone.stop();
         self.drone.replace(drone);
+        Ok(())
+    }
+
+    /// Takes off if needed and flies a step of moving sideways and then of turning, each after hovering.
+    /// The velocities are in pixels and radians per second: the sideways speed of the navdata is converted
+    /// to the pixels of the ground camera at the current height, whichever camera is used for following
+    /// (the GroundProjection hands the measurements over in these pixels). The drone is left hovering.
+    pub fn measure_step_responses(&mut self) -> Result<(StepResponse, StepResponse), ParrotError> {
+        self.try_init()?;
+        self.try_takeoff()?;
+        let moving = self.fly_step((CALIBRATION_MOVE, 0.0))?;
+        let turning = self.fly_step((0.0, CALIBRATION_TURN))?;
+        self.stop();
+        Ok((moving, turning))
+    }
+
+    fn fly_step(&mut self, (left_right, turn): (f64, f64)) -> Result<StepResponse, ParrotError> {
+        let mut response = StepResponse::new(if turn != 0.0 { turn } else { left_right });
+        let flight_height = self.flight_height;
+        let width = self.video.get_width() as f64;
+        let start = Instant::now();
+        let mut last_yaw = None;
+        let mut last_time = -HOVER_TIME.as_secs_f64();
+        while start.elapsed() < HOVER_TIME + STEP_TIME {
+            if safety::emergency_requested() || self.flight.is_none() {
+                self.emergency_land();
+                return Err(ParrotError::EmergencyLanding);
+            }
+            let drone = self.drone.as_mut().ok_or(ParrotError::NotConnected)?;
+            let time = start.elapsed().as_secs_f64() - HOVER_TIME.as_secs_f64();
+            if time < 0.0 {
+                drone.mov(0.0, 0.0, 0.0, 0.0);
+            } else {
+                drone.mov(left_right as f32, 0.0, 0.0, turn as f32);
+            }
+
+            if turn != 0.0 {
+                // psi is in millidegrees
+                if let Some(psi) = ParrotController::read_value(drone, "demo_psi") {
+                    let yaw = (psi / 1000.0).to_radians();
+                    if let Some(last) = last_yaw {
+                        response.add_sample(time, normalize_angle(yaw - last) / (time - last_time));
+                    }
+                    last_yaw = Some(yaw);
+                }
+            } else if let Some(vy) = ParrotController::read_value(drone, "demo_vy") {
+                // vy is in mm/s, the altitude in cm
+                let height = drone.get_altitude().filter(|h| *h > 0).unwrap_or(flight_height) as f64;
+                let pixels_per_mm = CameraModel::ground().pixels_per_cm(width, height) / 10.0;
+                response.add_sample(time, vy * pixels_per_mm);
+            }
+            last_time = time;
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+        Ok(response)
+    }
+
+    fn read_value(drone: &mut Drone, field: &str) -> Option<f64> {
+        match drone.get_navdata(field) {
+            Some(NavDataValue::Int(a)) => Some(a as f64),
+            Some(NavDataValue::Uint(a)) => Some(a as f64),
+            Some(NavDataValue::Float(a)) => Some(a as f64),
+            _ => None,
+        }
+    }
+
+    /// Lands the drone immediately, without waiting for the follower, and ignores the
+    /// movement commands from then on. The follower is asked to stop too.
+    pub fn emergency_land(&mut self) {
+        if self.flight.is_none() {
+            return;
+        }
+        println!("Emergency landing!");
+        self.land();
+        if let Some(sx) = &self.stop_sender {
+            let _ = sx.send(0);
+        }
+    }
+}
+
+impl Controller for ParrotController {
+    /// The HatFollower can't handle errors, so a failed startup is only reported
+    /// and the connection is closed, which turns every later command into a no-op.
+    fn init(&mut self) {
+        if let Err(e) = self.try_init() {
+            println!("Could not initialize the drone: {}", e);
+            self.drone = None;
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if self.flight.is_some() {
+            self.land();
+            thread::sleep(LANDING_TIME);
+        }
+        self.drone.take();
+    }
+
+    fn takeoff(&mut self) {
+        if let Err(e) = self.try_takeoff() {
+            println!("Could not take off: {}", e);
+            self.drone = None;
+        }
     }
 
     fn land(&mut self) {
-        let mut drone = self.drone.take().unwrap();
-        drone.land();
-        self.drone.replace(drone);
+        if let Some(drone) = self.drone.as_mut() {
+            drone.land();
+        }
+        if let Some(flight) = self.flight.take() {
+            safety::landed(flight);
+        }
     }
 
     fn move_all(&mut self, left_right: f64, back_front: f64, down_up: f64, turn_left_right: f64) {
@@ -107,42 +385,109 @@ impl Controller for ParrotController {
             self.te.save_row("commands.txt",
                              format!("{}, {}, {}, {}", left_right, back_front, down_up, turn_left_right));
         }
-        let mut drone = self.drone.take().unwrap();
-        drone.mov(
-            left_right as f32,
-            back_front as f32,
-            down_up as f32,
-            turn_left_right as f32
-        );
-        self.drone.replace(drone);
+        // After landing (e.g. an emergency) the follower may still send commands
+        if self.flight.is_none() {
+            return;
+        }
+        if let Some(drone) = self.drone.as_mut() {
+            // The height drifts during long follows, which would change the apparent size of the hat
+            let up = (down_up + self.altitude_hold.correct(drone)).max(-1.0).min(1.0);
+            drone.mov(
+                left_right as f32,
+                back_front as f32,
+                up as f32,
+                turn_left_right as f32
+            );
+        }
     }
 
     fn stop(&mut self) {
-        let mut drone = self.drone.take().unwrap();
-        drone.stop();
-        self.drone.replace(drone);
+        if let Some(drone) = self.drone.as_mut() {
+            drone.stop();
+        }
     }
 
     fn get_video_height(&self) -> usize {
-        360
+        self.video.get_height()
     }
 
     fn get_video_width(&self) -> usize {
-        640
+        self.video.get_width()
     }
 
     fn get_next_frame(&mut self, img: &mut Mat) -> opencv::Result<bool> {
-        self.video.read(img)
+        // Called on every frame, even when the follower doesn't move the drone
+        if safety::emergency_requested() {
+            self.emergency_land();
+        }
+        self.poll_battery();
+        if let (Some(recorder), Some(drone)) = (&self.telemetry, self.drone.as_mut()) {
+            recorder.record(drone);
+        }
+        if let Some(drone) = self.drone.as_mut() {
+            // 0 is read on the ground and before the first navdata
+            let altitude = drone.get_altitude().filter(|a| *a > 0);
+            if let Ok(mut level) = self.altitude.lock() {
+                *level = altitude;
+            }
+        }
+
+        // A frame the follower has seen already is never given again (it would be detected again as if it
+        // was new), while the video is stalled the drone hovers and the emergencies are still handled
+        let start = Instant::now();
+        loop {
+            if self.video.next_frame(img)? {
+                if let Ok(mut timestamp) = self.timestamps.lock() {
+                    *timestamp = self.video.get_timestamp();
+                }
+                if self.video_stalled {
+                    println!("The video recovered (reconnects so far: {})", self.video.get_reconnect_count());
+                    self.video_stalled = false;
+                }
+                return Ok(true);
+            }
+            thread::sleep(Duration::from_millis(5));
+
+            if start.elapsed() < FRAME_TIMEOUT {
+                continue;
+            }
+            if !self.video_stalled {
+                println!("No new frame for {} ms, hovering (reconnects so far: {})",
+                         FRAME_TIMEOUT.as_millis(), self.video.get_reconnect_count());
+                self.video_stalled = true;
+                self.stop();
+            }
+            if safety::emergency_requested() {
+                self.emergency_land();
+                return Ok(false);
+            }
+            if self.poll_battery() {
+                return Ok(false);
+            }
+            if start.elapsed() >= VIDEO_LOST_TIMEOUT {
+                println!("No new frame for {} seconds, landing!", VIDEO_LOST_TIMEOUT.as_secs());
+                self.stop_follower();
+                return Ok(false);
+            }
+        }
     }
 
     fn get_kv(&self) -> f64 {
-        // TODO: NEEDS TESTING
-        0.003
+        self.gains.kv
     }
 
     fn get_ka(&self) -> f64 {
-        // Turning is currently turned off.
-        // TODO: NEEDS TESTING
-        0.0
+        self.gains.ka
+    }
+}
+
+/// Lands the drone if the controller goes away while flying, e.g. when the follower thread panics.
+impl Drop for ParrotController {
+    fn drop(&mut self) {
+        if self.flight.is_some() {
+            println!("The controller was dropped while flying, landing!");
+            self.land();
+            thread::sleep(LANDING_TIME);
+        }
     }
-}
\ No newline at end of file
+}
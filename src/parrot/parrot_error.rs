@@ -0,0 +1,41 @@
+use std::fmt;
+
+/// Errors that can occur while connecting to the drone or getting it into the air.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParrotError {
+    /// The controller settings (file: reason) could not be read.
+    ConfigUnreadable(String),
+    /// The sockets to the drone (address: reason) could not be opened.
+    ConnectionFailed(String),
+    /// The video stream at the given URL could not be opened.
+    VideoUnavailable(String),
+    /// The drone did not respond to the startup sequence.
+    StartupFailed(String),
+    /// The drone was asked to take off before it was initialized.
+    NotInitialized,
+    /// The connection to the drone was already closed.
+    NotConnected,
+    /// The flight height (target, reached) was not reached in time, the drone was landed.
+    HeightNotReached(i32, i32),
+    /// The battery (percentage) is at or below the critical level, the drone can't take off.
+    BatteryLow(u32),
+    /// An emergency landing was requested during the takeoff or the calibration.
+    EmergencyLanding,
+}
+
+impl fmt::Display for ParrotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParrotError::ConfigUnreadable(reason) => write!(f, "could not read the controller settings from {}", reason),
+            ParrotError::ConnectionFailed(reason) => write!(f, "could not connect to the drone at {}", reason),
+            ParrotError::VideoUnavailable(url) => write!(f, "the video stream at {} could not be opened", url),
+            ParrotError::StartupFailed(reason) => write!(f, "drone startup failed: {}", reason),
+            ParrotError::NotInitialized => write!(f, "the drone was not initialized before takeoff"),
+            ParrotError::NotConnected => write!(f, "the connection to the drone is closed"),
+            ParrotError::HeightNotReached(target, reached) => write!(f,
+                "{} cm was not reached within 10 seconds (reached {} cm), the drone was landed", target, reached),
+            ParrotError::BatteryLow(level) => write!(f, "the battery is too low to take off ({}%)", level),
+            ParrotError::EmergencyLanding => write!(f, "the flight was interrupted by an emergency landing"),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{process, thread};
+
+use crate::parrot::drone::DroneHandle;
+
+/// Time the drone needs to land, the connection is kept open until then.
+pub const LANDING_TIME: Duration = Duration::from_secs(10);
+
+/// Set when the drones have to land immediately, checked by every ParrotController on each frame.
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
+/// The drones that are currently in the air, so that they can be landed without their controllers.
+static FLYING: Mutex<Vec<(usize, DroneHandle)>> = Mutex::new(Vec::new());
+static NEXT_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// When the last land (or motor cutoff) command was sent.
+static LAST_LANDING: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Makes every flying drone land right away. Their ParrotControllers also land on their next frame
+/// and stop their followers.
+pub fn request_emergency_landing() {
+    println!("Emergency landing requested!");
+    EMERGENCY.store(true, Ordering::SeqCst);
+    for drone in take_flying() {
+        drone.land();
+    }
+}
+
+/// Cuts the motors of every flying drone, they fall from where they are. Only for when even
+/// the landing would be dangerous, otherwise request_emergency_landing should be used.
+pub fn cut_motors() {
+    println!("Cutting the motors!");
+    EMERGENCY.store(true, Ordering::SeqCst);
+    for drone in take_flying() {
+        drone.emergency();
+    }
+}
+
+pub fn emergency_requested() -> bool {
+    EMERGENCY.load(Ordering::SeqCst)
+}
+
+/// Called before a new takeoff, so that an earlier emergency doesn't land the drone again.
+pub(crate) fn clear_emergency() {
+    EMERGENCY.store(false, Ordering::SeqCst);
+}
+
+/// Registers a drone that took off, returns the id with which it is unregistered after landing.
+pub(crate) fn took_off(drone: DroneHandle) -> usize {
+    let flight = NEXT_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    if let Ok(mut flying) = FLYING.lock() {
+        flying.push((flight, drone));
+    }
+    flight
+}
+
+/// Called after the land command of a flight was sent. The flight may be unregistered already,
+/// if it was landed through the emergency functions.
+pub(crate) fn landed(flight: usize) {
+    if let Ok(mut flying) = FLYING.lock() {
+        flying.retain(|(f, _)| *f != flight);
+    }
+    note_landing();
+}
+
+fn take_flying() -> Vec<DroneHandle> {
+    let drones = match FLYING.lock() {
+        Ok(mut flying) => flying.drain(..).map(|(_, drone)| drone).collect(),
+        Err(_) => Vec::new(),
+    };
+    if !drones.is_empty() {
+        note_landing();
+    }
+    drones
+}
+
+fn note_landing() {
+    if let Ok(mut last) = LAST_LANDING.lock() {
+        *last = Some(Instant::now());
+    }
+}
+
+/// Time until the drone landed by the last land command is down.
+fn remaining_landing_time() -> Duration {
+    match LAST_LANDING.lock().ok().and_then(|last| *last) {
+        Some(last) => LANDING_TIME.checked_sub(last.elapsed()).unwrap_or_default(),
+        None => Duration::from_secs(0),
+    }
+}
+
+/// On Ctrl+C (SIGINT / SIGTERM) the flying drones are landed, and the process exits only
+/// after LANDING_TIME has passed since the last land command.
+pub fn install_signal_handler() {
+    let result = ctrlc::set_handler(|| {
+        request_emergency_landing();
+        loop {
+            let remaining = remaining_landing_time();
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            thread::sleep(remaining.min(Duration::from_millis(100)));
+        }
+        process::exit(130);
+    });
+    if let Err(e) = result {
+        println!("Could not install the signal handler, Ctrl+C won't land the drone: {}", e);
+    }
+}
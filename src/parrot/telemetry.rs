@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::parrot::drone::{Drone, NavDataValue};
+
+/// The navdata values that are recorded, in the order of the columns.
+const NAVDATA_FIELDS: [&str; 9] = [
+    "demo_altitude",
+    "demo_battery",
+    "demo_theta",
+    "demo_phi",
+    "demo_psi",
+    "demo_vx",
+    "demo_vy",
+    "demo_vz",
+    "demo_ctrl_state",
+];
+const HEADER: &str = "time,altitude,battery,pitch,roll,yaw,vx,vy,vz,control_state";
+
+/// Records the navdata of the drone to a CSV file, one row per frame with the unix time in seconds.
+/// The rows are written by a background thread, so recording only costs reading the navdata.
+pub struct TelemetryRecorder {
+    sender: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl TelemetryRecorder {
+    pub fn new(filename: &str) -> io::Result<TelemetryRecorder> {
+        let mut file = BufWriter::new(File::create(filename)?);
+        writeln!(file, "{}", HEADER)?;
+
+        let (sender, receiver) = channel::<String>();
+        let writer = thread::spawn(move || {
+            // Ends when the recorder is dropped and the channel is closed
+            for row in receiver {
+                if writeln!(file, "{}", row).is_err() {
+                    break;
+                }
+            }
+            let _ = file.flush();
+        });
+
+        Ok(TelemetryRecorder {
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Reads the current navdata of the drone and queues it to be written.
+    pub fn record(&self, drone: &mut Drone) {
+        let time = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(_) => 0.0,
+        };
+        let values: Vec<String> = NAVDATA_FIELDS.iter()
+            .map(|field| match drone.get_navdata(field) {
+                Some(NavDataValue::Int(a)) => a.to_string(),
+                Some(NavDataValue::Uint(a)) => a.to_string(),
+                Some(NavDataValue::Float(a)) => a.to_string(),
+                _ => String::from("-"),
+            })
+            .collect();
+
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(format!("{},{}", time, values.join(",")));
+        }
+    }
+}
+
+impl Drop for TelemetryRecorder {
+    /// Waits for the queued rows to be written.
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use opencv::videoio::{VideoCapture, CAP_ANY, CAP_PROP_FRAME_WIDTH, CAP_PROP_FRAME_HEIGHT, CAP_PROP_POS_MSEC, VideoCaptureTrait};
+use opencv::core::{Mat, MatTrait, MatExprTrait, Size, CV_8U};
+
+use crate::parrot::parrot_error::ParrotError;
+use crate::parrot::connection::{DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT};
+
+/// The stream is reopened after this many failed reads in a row...
+const MAX_FAILED_READS: usize = 10;
+/// ...or if no frame was read for this long.
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time open waits for the first frame of the stream.
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+/// Waiting time after a failed read or reconnect attempt.
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+struct LatestFrame {
+    frame: Mat,
+    sequence: u64,
+    /// Time of the frame in seconds
+    timestamp: f64,
+}
+
+/// Reads the video of the drone in a background thread and reconnects if the stream fails or stalls,
+/// so that the latest frame is always available without blocking the follower.
+/// Reads of a stalled TCP stream only return after OpenCV's own timeout, so the reconnect can be delayed by it.
+pub struct VideoStream {
+    latest: Arc<Mutex<LatestFrame>>,
+    running: Arc<AtomicBool>,
+    reconnects: Arc<AtomicUsize>,
+    last_sequence: u64,
+    last_timestamp: Option<f64>,
+    width: usize,
+    height: usize,
+}
+
+impl VideoStream {
+    pub fn open(url: &str) -> Result<VideoStream, ParrotError> {
+        let video = VideoStream::connect(url).ok_or_else(|| ParrotError::VideoUnavailable(String::from(url)))?;
+        let (width, height) = VideoStream::frame_size(&video);
+        let latest = Arc::new(Mutex::new(LatestFrame { frame: VideoStream::empty_frame(), sequence: 0, timestamp: 0.0 }));
+        let running = Arc::new(AtomicBool::new(true));
+        let reconnects = Arc::new(AtomicUsize::new(0));
+
+        let (t_latest, t_running, t_reconnects, t_url) = (latest.clone(), running.clone(), reconnects.clone(), String::from(url));
+        thread::spawn(move || VideoStream::read_frames(video, t_url, t_latest, t_running, t_reconnects));
+
+        // A stream that opens but never sends a frame would leave the follower without a picture
+        let start = Instant::now();
+        while latest.lock().map(|l| l.sequence == 0).unwrap_or(true) {
+            if start.elapsed() >= FIRST_FRAME_TIMEOUT {
+                running.store(false, Ordering::SeqCst);
+                return Err(ParrotError::VideoUnavailable(String::from(url)));
+            }
+            thread::sleep(RETRY_DELAY);
+        }
+
+        Ok(VideoStream {
+            latest,
+            running,
+            reconnects,
+            last_sequence: 0,
+            last_timestamp: None,
+            width,
+            height,
+        })
+    }
+
+    /// The size of the frames as reported by the stream, the AR.Drone's if it is unknown.
+    fn frame_size(video: &VideoCapture) -> (usize, usize) {
+        let width = video.get(CAP_PROP_FRAME_WIDTH).unwrap_or(0.0);
+        let height = video.get(CAP_PROP_FRAME_HEIGHT).unwrap_or(0.0);
+        if width >= 1.0 && height >= 1.0 {
+            (width as usize, height as usize)
+        } else {
+            (DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT)
+        }
+    }
+
+    pub fn get_width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    fn empty_frame() -> Mat {
+        Mat::zeros_size(Size::new(1, 1), CV_8U).unwrap().to_mat().unwrap()
+    }
+
+    fn connect(url: &str) -> Option<VideoCapture> {
+        match VideoCapture::from_file(url, CAP_ANY) {
+            Ok(video) if video.is_opened().unwrap_or(false) => Some(video),
+            _ => None,
+        }
+    }
+
+    fn read_frames(mut video: VideoCapture, url: String, latest: Arc<Mutex<LatestFrame>>,
+                   running: Arc<AtomicBool>, reconnects: Arc<AtomicUsize>) {
+        let mut frame = VideoStream::empty_frame();
+        let mut failed_reads = 0;
+        let mut last_frame = Instant::now();
+        let opened = Instant::now();
+
+        while running.load(Ordering::SeqCst) {
+            match video.read(&mut frame) {
+                Ok(true) => {
+                    failed_reads = 0;
+                    last_frame = Instant::now();
+                    // The position in the stream, or the arrival time if the stream doesn't tell it
+                    let position = video.get(CAP_PROP_POS_MSEC).unwrap_or(0.0);
+                    let timestamp = if position > 0.0 { position / 1000.0 } else { opened.elapsed().as_secs_f64() };
+                    if let Ok(mut latest) = latest.lock() {
+                        if frame.copy_to(&mut latest.frame).is_ok() {
+                            latest.sequence += 1;
+                            latest.timestamp = timestamp;
+                        }
+                    }
+                    continue;
+                }
+                _ => {
+                    failed_reads += 1;
+                }
+            }
+
+            if failed_reads >= MAX_FAILED_READS || last_frame.elapsed() >= STALL_TIMEOUT {
+                let count = reconnects.fetch_add(1, Ordering::SeqCst) + 1;
+                println!("The video stream stalled, reconnecting (reconnect #{})", count);
+                if let Some(reopened) = VideoStream::connect(url.as_str()) {
+                    video = reopened;
+                }
+                failed_reads = 0;
+                last_frame = Instant::now();
+            }
+            thread::sleep(RETRY_DELAY);
+        }
+    }
+
+    /// Copies the latest frame into img if it wasn't returned yet, returns whether there was a new frame.
+    pub fn next_frame(&mut self, img: &mut Mat) -> opencv::Result<bool> {
+        let latest = match self.latest.lock() {
+            Ok(latest) => latest,
+            Err(_) => return Ok(false),
+        };
+        if latest.sequence == self.last_sequence {
+            return Ok(false);
+        }
+        latest.frame.copy_to(img)?;
+        self.last_sequence = latest.sequence;
+        self.last_timestamp = Some(latest.timestamp);
+        Ok(true)
+    }
+
+    /// The time of the frame last returned by next_frame in seconds.
+    pub fn get_timestamp(&self) -> Option<f64> {
+        self.last_timestamp
+    }
+
+    /// Number of times the stream had to be reopened.
+    pub fn get_reconnect_count(&self) -> usize {
+        self.reconnects.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for VideoStream {
+    /// The reading thread stops after its current read, it is not waited for as a stalled read can take long.
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
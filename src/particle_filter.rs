@@ -0,0 +1,359 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use rulinalg::matrix::Matrix;
+
+use opencv::core::Scalar;
+
+use rust_drone_follow::traits::Filter;
+use rust_drone_follow::models::GeometricPoint;
+use rust_drone_follow::utils::MarkerDrawer;
+use rust_drone_follow::utils::opencv_custom::{get_blue, get_green};
+
+use crate::kalman_filter::{KalmanSettings, KalmanError, FrameClock, FrameTimestamp, covariance_matrix, normalize_angle};
+
+/// Share of the particles that are placed around a detection when the track may be lost,
+/// so that a hat reappearing far from where it was lost is picked up again.
+const RECOVERY_FRACTION: f64 = 0.05;
+/// The track is taken as lost when the effective sample size falls below this share of the particles.
+const COLLAPSED_SAMPLE_SIZE: f64 = 0.1;
+/// At most this many particles are drawn on the image.
+const DRAWN_PARTICLES: usize = 200;
+
+#[derive(Debug, Clone)]
+struct Particle {
+    x: f64,
+    y: f64,
+    angle: f64,
+    vx: f64,
+    vy: f64,
+    weight: f64,
+}
+
+/// Samples a normal distribution with zero mean (Box-Muller transform).
+fn gaussian<R: Rng>(rng: &mut R, std_dev: f64) -> f64 {
+    let u1: f64 = rng.gen_range(std::f64::EPSILON, 1.0);
+    let u2: f64 = rng.gen_range(0.0, 1.0);
+    std_dev * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Particle filter over the same (x, y, angle, vx, vy) state as the KalmanFilter.
+/// It can keep several hypotheses at once (two similar blobs, a hat reappearing somewhere else),
+/// which a single Gaussian can't.
+pub struct ParticleFilter {
+    particles: Vec<Particle>,
+    rng: StdRng,
+    /// Standard deviations of the process noise of a single frame (x, y, angle, vx, vy)
+    process_std: [f64; 5],
+    /// Variances of the measurement noise (x, y, angle)
+    measurement_var: [f64; 3],
+    est_v_loss: f64,
+    point: Option<GeometricPoint>,
+    angle: f64,
+    vx: f64,
+    vy: f64,
+    missed_frames: usize,
+    max_coast_frames: usize,
+    clock: FrameClock,
+}
+
+impl ParticleFilter {
+    /// Creates a new ParticleFilter, the diagonals of Q and R are used as the noise of the particles.
+    pub fn from_settings(settings: &KalmanSettings) -> Result<ParticleFilter, KalmanError> {
+        let q = covariance_matrix("Q", 5, &settings.q)?;
+        let r = covariance_matrix("R", 3, &settings.r)?;
+        let diag = |m: &Matrix<f64>, i: usize| m[[i, i]];
+
+        Ok(ParticleFilter {
+            particles: vec![Particle { x: 0.0, y: 0.0, angle: 0.0, vx: 0.0, vy: 0.0, weight: 1.0 }; settings.particle_count.max(1)],
+            rng: StdRng::seed_from_u64(settings.seed),
+            process_std: [diag(&q, 0).sqrt(), diag(&q, 1).sqrt(), diag(&q, 2).sqrt(), diag(&q, 3).sqrt(), diag(&q, 4).sqrt()],
+            measurement_var: [diag(&r, 0), diag(&r, 1), diag(&r, 2)],
+            est_v_loss: settings.est_v_loss,
+            point: None,
+            angle: 0.0,
+            vx: 0.0,
+            vy: 0.0,
+            missed_frames: 0,
+            max_coast_frames: settings.max_coast_frames,
+            clock: FrameClock::new(settings.frame_time),
+        })
+    }
+
+    /// Measures the time between the updates with the timestamps of the frames, see FrameTimestamp.
+    pub fn with_frame_timestamps(mut self, timestamps: FrameTimestamp) -> ParticleFilter {
+        self.clock.set_timestamps(timestamps);
+        self
+    }
+
+    /// A particle close to the given measurement.
+    fn particle_around(&mut self, x: f64, y: f64, angle: Option<f64>, weight: f64) -> Particle {
+        let [var_x, var_y, var_a] = self.measurement_var;
+        Particle {
+            x: x + gaussian(&mut self.rng, var_x.sqrt()),
+            y: y + gaussian(&mut self.rng, var_y.sqrt()),
+            angle: match angle {
+                Some(a) => normalize_angle(a + gaussian(&mut self.rng, var_a.sqrt())),
+                None => self.rng.gen_range(-std::f64::consts::PI, std::f64::consts::PI),
+            },
+            vx: gaussian(&mut self.rng, self.process_std[3]),
+            vy: gaussian(&mut self.rng, self.process_std[4]),
+            weight,
+        }
+    }
+
+    fn predict(&mut self, dt: f64) {
+        let v_loss = self.est_v_loss.powf(dt);
+        let std: Vec<f64> = self.process_std.iter().map(|s| s * dt.sqrt()).collect();
+        for i in 0..self.particles.len() {
+            let noise = [
+                gaussian(&mut self.rng, std[0]),
+                gaussian(&mut self.rng, std[1]),
+                gaussian(&mut self.rng, std[2]),
+                gaussian(&mut self.rng, std[3]),
+                gaussian(&mut self.rng, std[4]),
+            ];
+            let p = &mut self.particles[i];
+            p.x += p.vx * dt + noise[0];
+            p.y += p.vy * dt + noise[1];
+            p.angle = normalize_angle(p.angle + noise[2]);
+            p.vx = p.vx * v_loss + noise[3];
+            p.vy = p.vy * v_loss + noise[4];
+        }
+    }
+
+    fn weigh(&mut self, x: f64, y: f64, angle: Option<f64>) {
+        let [var_x, var_y, var_a] = self.measurement_var;
+        for p in self.particles.iter_mut() {
+            let mut exponent = (p.x - x).powi(2) / var_x + (p.y - y).powi(2) / var_y;
+            if let Some(a) = angle {
+                exponent += normalize_angle(p.angle - a).powi(2) / var_a;
+            }
+            p.weight *= (-0.5 * exponent).exp();
+        }
+
+        let sum: f64 = self.particles.iter().map(|p| p.weight).sum();
+        if sum > 0.0 && sum.is_finite() {
+            self.particles.iter_mut().for_each(|p| p.weight /= sum);
+        } else {
+            // Every particle is far away: the hat has to be searched for around the detection
+            let n = self.particles.len();
+            for i in 0..n {
+                self.particles[i] = self.particle_around(x, y, angle, 1.0 / n as f64);
+            }
+        }
+    }
+
+    /// Replaces some of the weighed particles with ones around the detection. They get the average weight
+    /// instead of being weighed, so a false detection can't take over the estimate, but if the detections
+    /// keep coming from there the resampling moves the particles over in a few frames.
+    fn recover(&mut self, x: f64, y: f64, angle: Option<f64>) {
+        let n = self.particles.len();
+        let recovered = (n as f64 * RECOVERY_FRACTION) as usize;
+        for _i in 0..recovered {
+            let index = self.rng.gen_range(0, n);
+            self.particles[index] = self.particle_around(x, y, angle, 1.0 / n as f64);
+        }
+        let sum: f64 = self.particles.iter().map(|p| p.weight).sum();
+        self.particles.iter_mut().for_each(|p| p.weight /= sum);
+    }
+
+    /// Number of particles that would carry the same information if they had equal weights.
+    fn effective_sample_size(&self) -> f64 {
+        1.0 / self.particles.iter().map(|p| p.weight * p.weight).sum::<f64>()
+    }
+
+    /// Systematic resampling, done when most of the weight is carried by a few particles.
+    fn resample(&mut self) {
+        let n = self.particles.len();
+        if self.effective_sample_size() > n as f64 / 2.0 {
+            return;
+        }
+
+        let start = self.rng.gen_range(0.0, 1.0 / n as f64);
+        let mut resampled = Vec::with_capacity(n);
+        let mut cumulative = self.particles[0].weight;
+        let mut j = 0;
+        for i in 0..n {
+            let target = start + i as f64 / n as f64;
+            while cumulative < target && j + 1 < n {
+                j += 1;
+                cumulative += self.particles[j].weight;
+            }
+            let mut p = self.particles[j].clone();
+            p.weight = 1.0 / n as f64;
+            resampled.push(p);
+        }
+        self.particles = resampled;
+    }
+
+    fn update_mean(&mut self) {
+        let (mut x, mut y, mut vx, mut vy, mut sin, mut cos) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+        for p in self.particles.iter() {
+            x += p.weight * p.x;
+            y += p.weight * p.y;
+            vx += p.weight * p.vx;
+            vy += p.weight * p.vy;
+            sin += p.weight * p.angle.sin();
+            cos += p.weight * p.angle.cos();
+        }
+        self.point = Some(GeometricPoint::new(x as i32, y as i32));
+        self.angle = sin.atan2(cos);
+        self.vx = vx;
+        self.vy = vy;
+    }
+
+    /// Updates the estimation with a measurement taken dt frames after the previous one.
+    pub fn update_estimation_dt(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, dt: f64) {
+        match point {
+            Some(p) => {
+                let (x, y) = (p.x as f64, p.y as f64);
+                if self.point.is_none() {
+                    // Nothing is tracked, every particle starts around the detection
+                    let n = self.particles.len();
+                    for i in 0..n {
+                        self.particles[i] = self.particle_around(x, y, angle, 1.0 / n as f64);
+                    }
+                    self.weigh(x, y, angle);
+                } else {
+                    self.predict(dt);
+                    self.weigh(x, y, angle);
+                    // After coasting or when the particles barely explain the detection, the hat may be
+                    // somewhere else than where it is tracked
+                    let collapsed = self.effective_sample_size() < self.particles.len() as f64 * COLLAPSED_SAMPLE_SIZE;
+                    if self.missed_frames > 0 || collapsed {
+                        self.recover(x, y, angle);
+                    }
+                }
+                self.update_mean();
+                self.resample();
+                self.missed_frames = 0;
+            }
+            None => {
+                if self.point.is_none() {
+                    return;
+                }
+                self.predict(dt);
+                self.update_mean();
+                self.missed_frames += 1;
+                if self.missed_frames > self.max_coast_frames {
+                    self.point = None;
+                }
+            }
+        }
+    }
+}
+
+impl Filter for ParticleFilter {
+    fn update_estimation(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, _cert: f64) {
+        let dt = self.clock.elapsed_frames();
+        self.update_estimation_dt(point, angle, dt);
+    }
+
+    fn get_estimated_position(&self) -> Option<GeometricPoint> {
+        self.point.as_ref().map(|p| p.clone())
+    }
+
+    fn get_estimated_angle(&self) -> f64 {
+        self.angle
+    }
+
+    fn get_estimated_vx(&self) -> f64 {
+        self.vx
+    }
+
+    fn get_estimated_vy(&self) -> f64 {
+        self.vy
+    }
+
+    /// Compares the spread of the particles to the measurement noise, like the KalmanFilter does with P.
+    fn get_estimation_certainty(&self) -> f64 {
+        match &self.point {
+            Some(e) => {
+                let variance = self.particles.iter()
+                    .map(|p| p.weight * ((p.x - e.x as f64).powi(2) + (p.y - e.y as f64).powi(2)) / 2.0)
+                    .sum::<f64>();
+                let measurement_variance = (self.measurement_var[0] + self.measurement_var[1]) / 2.0;
+                if variance <= 0.0 {
+                    return 1.0;
+                }
+                (measurement_variance / variance).min(1.0)
+            }
+            None => 0.0
+        }
+    }
+
+    fn draw_on_image(&self, m_d: &mut MarkerDrawer) {
+        let step = (self.particles.len() / DRAWN_PARTICLES).max(1);
+        for p in self.particles.iter().step_by(step) {
+            m_d.point(&GeometricPoint::new(p.x as i32, p.y as i32), get_green());
+        }
+        if let Some(p) = &self.point {
+            m_d.point(p, get_blue());
+
+            let k = 10.0;
+            let other_point = GeometricPoint::new(p.x + (k * self.angle.cos()) as i32, p.y + (k * self.angle.sin()) as i32);
+            m_d.line(p, &other_point, Scalar::new(255.0, 255.0, 255.0, 255.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(filter: &ParticleFilter) -> Option<(i32, i32)> {
+        filter.get_estimated_position().map(|p| (p.x, p.y))
+    }
+
+    fn distance(p: &GeometricPoint, (x, y): (f64, f64)) -> f64 {
+        ((p.x as f64 - x).powi(2) + (p.y as f64 - y).powi(2)).sqrt()
+    }
+
+    /// Two filters with the same seed give exactly the same estimates on the same detections.
+    #[test]
+    fn seed_test() {
+        let mut first = ParticleFilter::from_settings(&KalmanSettings::default()).unwrap();
+        let mut second = ParticleFilter::from_settings(&KalmanSettings::default()).unwrap();
+        let mut other = ParticleFilter::from_settings(&KalmanSettings { seed: 7, ..KalmanSettings::default() }).unwrap();
+
+        for i in 0..50 {
+            let point = if i % 10 == 9 { None } else { Some(GeometricPoint::new(100 + 2 * i, 50 - i)) };
+            first.update_estimation_dt(point.clone(), Some(0.3), 1.0);
+            second.update_estimation_dt(point.clone(), Some(0.3), 1.0);
+            other.update_estimation_dt(point, Some(0.3), 1.0);
+
+            assert_eq!(position(&first), position(&second), "The estimates differ at frame {}", i);
+            assert_eq!(first.get_estimated_angle(), second.get_estimated_angle());
+            assert_eq!(first.get_estimated_vx(), second.get_estimated_vx());
+        }
+        assert_ne!(first.get_estimated_angle(), other.get_estimated_angle(), "The seed is not used!");
+    }
+
+    /// The hat disappears for a few frames and reappears far from where it was lost,
+    /// the particles have to find it again instead of staying where it was.
+    #[test]
+    fn recovery_test() {
+        let mut filter = ParticleFilter::from_settings(&KalmanSettings::default()).unwrap();
+        for _i in 0..30 {
+            filter.update_estimation_dt(Some(GeometricPoint::new(0, 0)), Some(0.0), 1.0);
+        }
+        for _i in 0..5 {
+            filter.update_estimation_dt(None, None, 1.0);
+        }
+        assert!(filter.get_estimated_position().is_some(), "The track was lost while coasting!");
+
+        let hat = (80.0, 40.0);
+        for _i in 0..10 {
+            filter.update_estimation_dt(Some(GeometricPoint::new(hat.0 as i32, hat.1 as i32)), Some(0.0), 1.0);
+        }
+        let estimate = filter.get_estimated_position().unwrap();
+        assert!(distance(&estimate, hat) < 10.0, "The hat was not found again: ({}, {})", estimate.x, estimate.y);
+
+        // So far from the particles that none of them explains the detection
+        let hat = (1000.0, -800.0);
+        filter.update_estimation_dt(Some(GeometricPoint::new(hat.0 as i32, hat.1 as i32)), Some(0.0), 1.0);
+        let estimate = filter.get_estimated_position().unwrap();
+        assert!(distance(&estimate, hat) < 10.0, "The hat was not found again: ({}, {})", estimate.x, estimate.y);
+    }
+}
@@ -0,0 +1,500 @@
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{UdpSocket, TcpListener, TcpStream, SocketAddr};
+use std::process::{Command, Stdio, ChildStdin, ChildStdout};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use opencv::core::{Mat, MatExprTrait, Size, CV_8U};
+use opencv::imgcodecs::imencode;
+use opencv::types::{VectorOfu8, VectorOfi32};
+
+use rust_drone_follow::traits::Controller;
+
+use crate::simulation::virtual_controller::VirtualController;
+use crate::simulation::traits::{MoveTactic, WindTactic};
+
+/// Ports of the AR.Drone 2.0
+pub const NAVDATA_PORT: u16 = 5554;
+pub const VIDEO_PORT: u16 = 5555;
+pub const AT_PORT: u16 = 5556;
+/// The emulator is reached on the loopback interface by default.
+pub const LOCAL_ADDRESS: &str = "127.0.0.1";
+
+/// Time between two simulated frames (and navdata packets)
+const TICK: Duration = Duration::from_millis(33);
+/// The drone climbs to this altitude (cm) on its own after takeoff, like the real one
+const TAKEOFF_ALTITUDE: f64 = 100.0;
+/// Vertical speed (cm/s) of a full up / down command
+const MAX_VERTICAL_SPEED: f64 = 70.0;
+/// Speed (cm/s) of the automatic takeoff and landing
+const TAKEOFF_SPEED: f64 = 50.0;
+/// Battery percentage lost per second of flight
+const BATTERY_DRAIN: f64 = 0.05;
+
+const REF_TAKEOFF: u32 = 1 << 9;
+const REF_EMERGENCY: u32 = 1 << 8;
+
+/// Header of the video frames of the AR.Drone 2.0 (Parrot Video Encapsulation)
+const PAVE_SIGNATURE: &[u8; 4] = b"PaVE";
+const PAVE_HEADER_SIZE: usize = 64;
+const PAVE_CODEC_H264: u8 = 4;
+const PAVE_FRAME_I: u8 = 1;
+const PAVE_FRAME_P: u8 = 2;
+/// Start of the access unit delimiter NAL unit, FFmpeg puts one before every frame (aud=1)
+const ACCESS_UNIT_DELIMITER: &[u8; 5] = &[0, 0, 0, 1, 9];
+
+/// What the emulated drone is doing, it can be inspected by the tests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmulatorState {
+    pub flying: bool,
+    /// Altitude in cm
+    pub altitude: f64,
+    /// Battery in percent
+    pub battery: f64,
+    /// Last movement command (left_right, back_front, down_up, turn_left_right)
+    pub command: (f64, f64, f64, f64),
+    pub takeoffs: usize,
+    pub landings: usize,
+    pub emergencies: usize,
+    pub at_commands: usize,
+}
+
+impl EmulatorState {
+    pub(crate) fn new() -> EmulatorState {
+        EmulatorState {
+            flying: false,
+            altitude: 0.0,
+            battery: 100.0,
+            command: (0.0, 0.0, 0.0, 0.0),
+            takeoffs: 0,
+            landings: 0,
+            emergencies: 0,
+            at_commands: 0,
+        }
+    }
+
+    /// Applies one AT command ("AT*NAME=seq,arg,...").
+    pub(crate) fn execute(&mut self, command: &str) {
+        let mut parts = command.trim().splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let args: Vec<i64> = parts.next().unwrap_or("").split(',')
+            .map(|a| a.trim().parse::<i64>().unwrap_or(0))
+            .collect();
+        self.at_commands += 1;
+
+        match name {
+            "AT*REF" => {
+                let arg = args.get(1).copied().unwrap_or(0) as u32;
+                if arg & REF_EMERGENCY != 0 {
+                    self.emergencies += 1;
+                    self.flying = false;
+                    self.altitude = 0.0;
+                } else if arg & REF_TAKEOFF != 0 && !self.flying {
+                    self.flying = true;
+                    self.takeoffs += 1;
+                } else if arg & REF_TAKEOFF == 0 && self.flying {
+                    self.flying = false;
+                    self.landings += 1;
+                }
+            }
+            "AT*PCMD" | "AT*PCMD_MAG" => {
+                // Floats are sent as the integer with the same bits
+                let value = |i: usize| f32::from_bits(args.get(i).copied().unwrap_or(0) as u32) as f64;
+                self.command = if args.get(1).copied().unwrap_or(0) == 0 {
+                    (0.0, 0.0, 0.0, 0.0)
+                } else {
+                    (value(2), -value(3), value(4), value(5))
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// Moves the altitude and the battery forward by dt seconds.
+    fn step(&mut self, dt: f64) {
+        if self.flying {
+            let climb = if self.altitude < TAKEOFF_ALTITUDE { TAKEOFF_SPEED } else { 0.0 };
+            self.altitude = (self.altitude + (climb + self.command.2 * MAX_VERTICAL_SPEED) * dt).max(0.0);
+            self.battery = (self.battery - BATTERY_DRAIN * dt).max(0.0);
+        } else {
+            self.altitude = (self.altitude - TAKEOFF_SPEED * dt).max(0.0);
+        }
+    }
+
+    /// Builds a navdata packet with the demo and the checksum options.
+    pub(crate) fn navdata_packet(&self, sequence: u32) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(172);
+        let push_u16 = |p: &mut Vec<u8>, v: u16| p.extend_from_slice(&v.to_le_bytes());
+        let push_u32 = |p: &mut Vec<u8>, v: u32| p.extend_from_slice(&v.to_le_bytes());
+
+        // Header: magic, drone state (flying, navdata demo), sequence, vision
+        push_u32(&mut packet, 0x5566_7788);
+        push_u32(&mut packet, (self.flying as u32) | (1 << 10));
+        push_u32(&mut packet, sequence);
+        push_u32(&mut packet, 0);
+
+        // Demo option
+        let (left_right, back_front, down_up, turn) = if self.flying { self.command } else { (0.0, 0.0, 0.0, 0.0) };
+        let control_state: u32 = if self.flying { 3 } else { 2 };
+        push_u16(&mut packet, 0);
+        push_u16(&mut packet, 148);
+        push_u32(&mut packet, control_state << 16);
+        push_u32(&mut packet, self.battery as u32);
+        // theta, phi, psi in millidegrees: tilt in the direction of the movement
+        push_u32(&mut packet, ((-back_front * 12_000.0) as f32).to_bits());
+        push_u32(&mut packet, ((left_right * 12_000.0) as f32).to_bits());
+        push_u32(&mut packet, ((turn * 100_000.0) as f32).to_bits());
+        push_u32(&mut packet, self.altitude as i32 as u32);
+        // vx, vy, vz in mm/s
+        push_u32(&mut packet, ((back_front * 1000.0) as f32).to_bits());
+        push_u32(&mut packet, ((left_right * 1000.0) as f32).to_bits());
+        push_u32(&mut packet, ((down_up * MAX_VERTICAL_SPEED * 10.0) as f32).to_bits());
+        push_u32(&mut packet, sequence);
+        // Camera matrices of the detection, not emulated
+        packet.extend_from_slice(&[0u8; 104]);
+
+        // Checksum option: the sum of all the bytes before it
+        let checksum = packet.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32));
+        push_u16(&mut packet, 0xFFFF);
+        push_u16(&mut packet, 8);
+        push_u32(&mut packet, checksum);
+        packet
+    }
+}
+
+/// Channels of the connected video clients, every encoded frame is sent to all of them.
+type VideoClients = Arc<Mutex<Vec<Sender<Arc<Vec<u8>>>>>>;
+
+fn broadcast(clients: &VideoClients, frame: Vec<u8>) {
+    let frame = Arc::new(frame);
+    if let Ok(mut clients) = clients.lock() {
+        // The clients that disconnected are dropped
+        clients.retain(|client| client.send(frame.clone()).is_ok());
+    }
+}
+
+/// Wraps an encoded H.264 frame in a PaVE header, the way the AR.Drone 2.0 sends it.
+fn pave_frame(payload: &[u8], (width, height): (u16, u16), frame_number: u32, timestamp: u32) -> Vec<u8> {
+    // The frame is a key frame if it contains an IDR slice (NAL unit type 5)
+    let key_frame = payload.windows(4).any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1F == 5);
+    let mut frame = Vec::with_capacity(PAVE_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(PAVE_SIGNATURE);
+    frame.push(2);
+    frame.push(PAVE_CODEC_H264);
+    frame.extend_from_slice(&(PAVE_HEADER_SIZE as u16).to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    // Encoded and displayed size
+    for size in &[width, height, width, height] {
+        frame.extend_from_slice(&size.to_le_bytes());
+    }
+    frame.extend_from_slice(&frame_number.to_le_bytes());
+    frame.extend_from_slice(&timestamp.to_le_bytes());
+    // Total chunks, chunk index, frame type, control
+    frame.extend_from_slice(&[1, 0, if key_frame { PAVE_FRAME_I } else { PAVE_FRAME_P }, 0]);
+    // Stream byte position, stream id, slices, SPS / PPS sizes, reserved
+    frame.extend_from_slice(&[0; 16]);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.resize(PAVE_HEADER_SIZE, 0);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Removes the complete access units (frames) from the start of the H.264 stream, the last one
+/// stays in the buffer until the delimiter of the next one arrives.
+fn split_access_units(stream: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let delimiters: Vec<usize> = stream.windows(ACCESS_UNIT_DELIMITER.len())
+        .enumerate()
+        .filter(|(_, w)| w == ACCESS_UNIT_DELIMITER)
+        .map(|(i, _)| i)
+        .collect();
+    let units = delimiters.windows(2).map(|d| stream[d[0]..d[1]].to_vec()).collect();
+    if let Some(last) = delimiters.last() {
+        stream.drain(..*last);
+    }
+    units
+}
+
+/// Starts FFmpeg to encode the JPEG frames to H.264, None if it is not installed.
+fn start_encoder() -> Option<(ChildStdin, ChildStdout)> {
+    let mut child = Command::new("ffmpeg")
+        .args(&["-loglevel", "error", "-f", "image2pipe", "-c:v", "mjpeg", "-framerate", "30", "-i", "-",
+            "-c:v", "libx264", "-preset", "ultrafast", "-tune", "zerolatency", "-g", "30",
+            "-x264-params", "aud=1", "-f", "h264", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    // FFmpeg exits when its input is closed
+    Some((child.stdin.take()?, child.stdout.take()?))
+}
+
+/// A stand-in for the AR.Drone that speaks its AT command (UDP), navdata (UDP) and video (TCP) ports,
+/// and shows the world of a VirtualController on its camera. The video is PaVE framed H.264 like
+/// the real drone's, encoded by FFmpeg; if it is not installed a stream of JPEG images is sent instead,
+/// which OpenCV (FFmpeg) reads as well. The emulator runs until it is dropped.
+pub struct DroneEmulator {
+    state: Arc<Mutex<EmulatorState>>,
+    running: Arc<AtomicBool>,
+}
+
+impl DroneEmulator {
+    /// Starts the emulator on the given local address, the ParrotController connects to it
+    /// when the same address is given in its ParrotConnection.
+    pub fn start<M, W>(address: &str, controller: VirtualController<M, W>) -> io::Result<DroneEmulator>
+        where M: MoveTactic + Send + 'static, W: WindTactic + Send + 'static {
+        let at_socket = UdpSocket::bind((address, AT_PORT))?;
+        let navdata_socket = UdpSocket::bind((address, NAVDATA_PORT))?;
+        let video_listener = TcpListener::bind((address, VIDEO_PORT))?;
+        at_socket.set_read_timeout(Some(TICK))?;
+        navdata_socket.set_read_timeout(Some(Duration::from_millis(1)))?;
+        video_listener.set_nonblocking(true)?;
+
+        let state = Arc::new(Mutex::new(EmulatorState::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let clients: VideoClients = Arc::new(Mutex::new(Vec::new()));
+
+        let (s, r) = (state.clone(), running.clone());
+        thread::spawn(move || DroneEmulator::receive_commands(at_socket, s, r));
+        let (s, r) = (state.clone(), running.clone());
+        thread::spawn(move || DroneEmulator::send_navdata(navdata_socket, s, r));
+        let (s, r, c) = (state.clone(), running.clone(), clients.clone());
+        thread::spawn(move || DroneEmulator::simulate(controller, s, r, c));
+        let r = running.clone();
+        thread::spawn(move || DroneEmulator::serve_video(video_listener, r, clients));
+
+        println!("Drone emulator listening on {} (navdata: {}, video: {}, AT: {})", address, NAVDATA_PORT, VIDEO_PORT, AT_PORT);
+        Ok(DroneEmulator { state, running })
+    }
+
+    /// A copy of the current state of the emulated drone.
+    pub fn get_state(&self) -> EmulatorState {
+        self.state.lock().map(|s| s.clone()).unwrap_or_else(|_| EmulatorState::new())
+    }
+
+    fn receive_commands(socket: UdpSocket, state: Arc<Mutex<EmulatorState>>, running: Arc<AtomicBool>) {
+        let mut buffer = [0u8; 4096];
+        while running.load(Ordering::SeqCst) {
+            let size = match socket.recv(&mut buffer) {
+                Ok(size) => size,
+                Err(_) => continue,
+            };
+            let text = String::from_utf8_lossy(&buffer[..size]);
+            if let Ok(mut state) = state.lock() {
+                for command in text.split('\r').filter(|c| c.starts_with("AT*")) {
+                    state.execute(command);
+                }
+            }
+        }
+    }
+
+    fn send_navdata(socket: UdpSocket, state: Arc<Mutex<EmulatorState>>, running: Arc<AtomicBool>) {
+        let mut buffer = [0u8; 64];
+        let mut client: Option<SocketAddr> = None;
+        let mut sequence = 1;
+        while running.load(Ordering::SeqCst) {
+            // The client asks for the navdata by sending any packet to the port
+            if let Ok((_, from)) = socket.recv_from(&mut buffer) {
+                client = Some(from);
+            }
+            if let Some(to) = client {
+                let packet = match state.lock() {
+                    Ok(state) => state.navdata_packet(sequence),
+                    Err(_) => break,
+                };
+                let _ = socket.send_to(&packet, to);
+                sequence += 1;
+            }
+            thread::sleep(TICK);
+        }
+    }
+
+    fn simulate<M, W>(mut controller: VirtualController<M, W>, state: Arc<Mutex<EmulatorState>>,
+                      running: Arc<AtomicBool>, clients: VideoClients)
+        where M: MoveTactic, W: WindTactic {
+        let mut img = Mat::zeros_size(Size::new(1, 1), CV_8U).unwrap().to_mat().unwrap();
+        let mut jpeg = VectorOfu8::new();
+        let size = (controller.get_video_width() as u16, controller.get_video_height() as u16);
+        let mut encoder = match start_encoder() {
+            Some((input, output)) => {
+                let (r, c) = (running.clone(), clients.clone());
+                thread::spawn(move || DroneEmulator::packetize(output, size, r, c));
+                Some(input)
+            }
+            None => {
+                println!("FFmpeg was not found, the emulated video is sent as JPEG images");
+                None
+            }
+        };
+        while running.load(Ordering::SeqCst) {
+            let (flying, (left_right, back_front, down_up, turn)) = match state.lock() {
+                Ok(mut state) => {
+                    state.step(TICK.as_secs_f64());
+                    (state.flying, state.command)
+                }
+                Err(_) => break,
+            };
+            if flying {
+                controller.move_all(left_right, back_front, down_up, turn);
+            } else {
+                controller.stop();
+            }
+
+            if let Ok(true) = controller.get_next_frame(&mut img) {
+                if let Ok(true) = imencode(".jpg", &img, &mut jpeg, &VectorOfi32::new()) {
+                    let encoded = match encoder.as_mut() {
+                        Some(input) => input.write_all(&jpeg.to_vec()).is_ok(),
+                        None => false,
+                    };
+                    if !encoded {
+                        if encoder.take().is_some() {
+                            println!("FFmpeg stopped, the emulated video is sent as JPEG images");
+                        }
+                        broadcast(&clients, jpeg.to_vec());
+                    }
+                }
+            }
+            thread::sleep(TICK);
+        }
+    }
+
+    /// Cuts the H.264 stream of FFmpeg into frames and sends them in PaVE headers.
+    fn packetize(mut output: ChildStdout, size: (u16, u16), running: Arc<AtomicBool>, clients: VideoClients) {
+        let start = Instant::now();
+        let mut stream = Vec::new();
+        let mut buffer = [0u8; 65536];
+        let mut frame_number = 0;
+        while running.load(Ordering::SeqCst) {
+            let read = match output.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            stream.extend_from_slice(&buffer[..read]);
+            for unit in split_access_units(&mut stream) {
+                frame_number += 1;
+                broadcast(&clients, pave_frame(&unit, size, frame_number, start.elapsed().as_millis() as u32));
+            }
+        }
+    }
+
+    fn serve_video(listener: TcpListener, running: Arc<AtomicBool>, clients: VideoClients) {
+        while running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let (sx, rx) = channel();
+                    if let Ok(mut clients) = clients.lock() {
+                        clients.push(sx);
+                    }
+                    let r = running.clone();
+                    thread::spawn(move || DroneEmulator::stream_video(stream, r, rx));
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(TICK),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Every frame is sent, a H.264 frame can't be decoded without the ones before it.
+    fn stream_video(mut stream: TcpStream, running: Arc<AtomicBool>, frames: Receiver<Arc<Vec<u8>>>) {
+        let _ = stream.set_nonblocking(false);
+        while running.load(Ordering::SeqCst) {
+            let frame = match frames.recv_timeout(TICK) {
+                Ok(frame) => frame,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            // The client disconnected
+            if stream.write_all(&frame).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for DroneEmulator {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parrot::parrot_controller::ParrotController;
+    use crate::parrot::connection::ParrotConnection;
+    use crate::parrot::battery::BatterySettings;
+    use crate::parrot::altitude_hold::AltitudeSettings;
+    use crate::simulation::movetactics::stand_still::StandStill;
+    use crate::simulation::windtactics::no_wind::NoWind;
+
+    #[test]
+    fn pave_header_test() {
+        let payload = [0, 0, 0, 1, 9, 0x10, 0, 0, 0, 1, 0x65, 0xAA];
+        let frame = pave_frame(&payload, (640, 360), 7, 1000);
+        assert_eq!(&frame[0..4], b"PaVE");
+        assert_eq!(frame[5], PAVE_CODEC_H264);
+        assert_eq!(u16::from_le_bytes([frame[6], frame[7]]) as usize, PAVE_HEADER_SIZE);
+        assert_eq!(u32::from_le_bytes([frame[8], frame[9], frame[10], frame[11]]) as usize, payload.len());
+        assert_eq!(u16::from_le_bytes([frame[12], frame[13]]), 640);
+        assert_eq!(u16::from_le_bytes([frame[14], frame[15]]), 360);
+        assert_eq!(u32::from_le_bytes([frame[20], frame[21], frame[22], frame[23]]), 7);
+        assert_eq!(frame[30], PAVE_FRAME_I, "An IDR slice was not marked as a key frame!");
+        assert_eq!(&frame[PAVE_HEADER_SIZE..], &payload[..]);
+
+        let p_frame = pave_frame(&[0, 0, 0, 1, 9, 0x30, 0, 0, 0, 1, 0x41], (640, 360), 8, 1033);
+        assert_eq!(p_frame[30], PAVE_FRAME_P);
+    }
+
+    #[test]
+    fn access_unit_split_test() {
+        let mut stream = vec![0, 0, 0, 1, 9, 0x10, 1, 2, 0, 0, 0, 1, 9, 0x30, 3, 0, 0, 0, 1, 9, 0x30];
+        let units = split_access_units(&mut stream);
+        assert_eq!(units, vec![vec![0, 0, 0, 1, 9, 0x10, 1, 2], vec![0, 0, 0, 1, 9, 0x30, 3]]);
+        // The last frame may not be complete yet
+        assert_eq!(stream, vec![0, 0, 0, 1, 9, 0x30]);
+        assert!(split_access_units(&mut stream).is_empty());
+    }
+
+    /// Flies the ParrotController against the DroneEmulator on the loopback interface: startup, takeoff
+    /// with the height climb, a few moves on the emulated video and landing. Needs OpenCV with FFmpeg
+    /// and the free ports of the drone, so it only runs with cargo test -- --ignored.
+    #[test]
+    #[ignore]
+    fn emulated_flight_test() {
+        let world = VirtualController::new(20.0, 1, 0.01, StandStill::new(), NoWind::new(), false);
+        let emulator = DroneEmulator::start(LOCAL_ADDRESS, world).expect("Could not start the emulator!");
+
+        let flight_height = 150;
+        let connection = ParrotConnection {
+            address: String::from(LOCAL_ADDRESS),
+            ..ParrotConnection::default()
+        };
+        let mut controller = ParrotController::new(
+                flight_height,
+                true,
+                &connection,
+                BatterySettings::default(),
+                AltitudeSettings::default())
+            .expect("Could not connect to the emulator!");
+        controller.try_init().expect("Startup failed!");
+        controller.try_takeoff().expect("Takeoff failed!");
+        let state = emulator.get_state();
+        assert!(state.flying && state.takeoffs == 1, "The emulator didn't take off!");
+        assert!(state.altitude >= flight_height as f64, "The flight height was not reached!");
+
+        let mut img = Mat::zeros_size(Size::new(1, 1), CV_8U).unwrap().to_mat().unwrap();
+        for _i in 0..30 {
+            assert!(controller.get_next_frame(&mut img).unwrap(), "No frame was received!");
+            controller.move_all(0.1, 0.0, 0.0, 0.0);
+        }
+        assert!(emulator.get_state().command.0 > 0.0, "The movement command did not arrive!");
+
+        controller.land();
+        thread::sleep(Duration::from_secs(1));
+        let state = emulator.get_state();
+        assert!(!state.flying && state.landings == 1, "The emulator didn't land!");
+    }
+}
@@ -0,0 +1,147 @@
+/// Physical parameters of the simulated drone, as read from the tenth line of config.controller.
+/// The times are in simulation steps (the VirtualController makes 1 + skip_frames steps per frame),
+/// the distances in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DynamicsSettings {
+    /// Mass of the drone, the velocity follows the tilt with a time constant of mass / drag (in steps, keep it above 1)
+    pub mass: f64,
+    /// Linear air drag, it also carries the drone along with the wind
+    pub drag: f64,
+    /// Maximal horizontal acceleration in pixels per step squared
+    pub max_acceleration: f64,
+    /// Time constant of the tilt following the movement commands
+    pub tilt_time_constant: f64,
+    /// Time constant of the turning speed following the turning commands
+    pub yaw_time_constant: f64,
+}
+
+impl Default for DynamicsSettings {
+    fn default() -> DynamicsSettings {
+        DynamicsSettings {
+            mass: 1.0,
+            drag: 0.1,
+            max_acceleration: 1.5,
+            tilt_time_constant: 4.0,
+            yaw_time_constant: 4.0,
+        }
+    }
+}
+
+impl DynamicsSettings {
+    /// Parses a line of the form "mass drag max_acceleration tilt_time_constant yaw_time_constant",
+    /// missing or invalid values are replaced by the defaults.
+    pub fn parse(line: &str) -> DynamicsSettings {
+        let default = DynamicsSettings::default();
+        let args: Vec<&str> = line.split_whitespace().collect::<Vec<&str>>();
+        let value = |i: usize, default: f64| match args.get(i).map(|a| a.parse::<f64>()) {
+            Some(Ok(v)) if v.is_finite() && v > 0.0 => v,
+            _ => default
+        };
+
+        DynamicsSettings {
+            mass: value(0, default.mass),
+            drag: value(1, default.drag),
+            max_acceleration: value(2, default.max_acceleration),
+            tilt_time_constant: value(3, default.tilt_time_constant),
+            yaw_time_constant: value(4, default.yaw_time_constant),
+        }
+    }
+
+    /// The line parse reads back.
+    pub fn to_line(&self) -> String {
+        format!("{} {} {} {} {}", self.mass, self.drag, self.max_acceleration, self.tilt_time_constant, self.yaw_time_constant)
+    }
+}
+
+/// Quadrotor-like motion of the simulated drone. The movement commands set the target tilt, which is reached
+/// with a lag; the tilt accelerates the drone against the drag of the air (which may move with the wind),
+/// limited to the maximal acceleration. The turning commands set the target turning speed, also reached with a lag.
+pub struct Dynamics {
+    settings: DynamicsSettings,
+    /// Velocity the drone settles at in calm air on full tilt, in pixels per step
+    speed: f64,
+    /// Tilt in both directions, as the share of the full tilt
+    tilt: (f64, f64),
+    velocity: (f64, f64),
+    turn_rate: f64,
+}
+
+impl Dynamics {
+    pub fn new(speed: f64, settings: DynamicsSettings) -> Dynamics {
+        Dynamics {
+            settings,
+            speed,
+            tilt: (0.0, 0.0),
+            velocity: (0.0, 0.0),
+            turn_rate: 0.0,
+        }
+    }
+
+    /// Advances the motion by one step with the given commands (left_right, back_front, turn_left_right)
+    /// and wind velocity, returns the movement (x, y, angle) during the step.
+    pub fn step(&mut self, (left_right, back_front, turn): (f64, f64, f64), (wind_x, wind_y): (f64, f64)) -> (f64, f64, f64) {
+        let DynamicsSettings { mass, drag, max_acceleration, tilt_time_constant, yaw_time_constant } = self.settings;
+        // A time constant shorter than a step is reached in that step
+        let tilt_share = (1.0 / tilt_time_constant).min(1.0);
+        let yaw_share = (1.0 / yaw_time_constant).min(1.0);
+
+        let (tilt_x, tilt_y) = self.tilt;
+        self.tilt = (
+            tilt_x + (left_right.max(-1.0).min(1.0) - tilt_x) * tilt_share,
+            tilt_y + (back_front.max(-1.0).min(1.0) - tilt_y) * tilt_share,
+        );
+        // The thrust of the full tilt balances the drag at the speed of the drone
+        let speed = self.speed;
+        let acceleration = |tilt: f64, velocity: f64, wind: f64| {
+            ((drag * speed * tilt - drag * (velocity - wind)) / mass).max(-max_acceleration).min(max_acceleration)
+        };
+        let (v_x, v_y) = self.velocity;
+        let (a_x, a_y) = (acceleration(self.tilt.0, v_x, wind_x), acceleration(self.tilt.1, v_y, wind_y));
+        self.velocity = (v_x + a_x, v_y + a_y);
+
+        self.turn_rate += (turn - self.turn_rate) * yaw_share;
+
+        // Moved with the average velocity of the step
+        ((v_x + self.velocity.0) / 2.0, (v_y + self.velocity.1) / 2.0, self.turn_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gives full tilt to the simulated drone from hovering and checks that it speeds up gradually within the
+    /// acceleration limit, settles at its speed, levels out when stopped, and drifts with the speed of the wind.
+    #[test]
+    fn dynamics_test() {
+        let settings = DynamicsSettings::default();
+        let mut dynamics = Dynamics::new(20.0, settings);
+        let mut last_speed = 0.0;
+        for i in 0..200 {
+            let (d_x, _, _) = dynamics.step((1.0, 0.0, 0.0), (0.0, 0.0));
+            if i == 0 {
+                assert!(d_x < 2.0, "The drone got moving instantly ({} px in the first step)", d_x);
+            }
+            assert!(d_x - last_speed <= settings.max_acceleration + 1e-9, "The acceleration limit was exceeded!");
+            last_speed = d_x;
+        }
+        assert!((last_speed - 20.0).abs() < 0.1, "The drone did not settle at its speed ({} px per step)", last_speed);
+
+        for _i in 0..200 {
+            last_speed = dynamics.step((0.0, 0.0, 0.0), (0.0, 0.0)).0;
+        }
+        assert!(last_speed.abs() < 0.1, "The drone did not stop!");
+
+        for _i in 0..200 {
+            last_speed = dynamics.step((0.0, 0.0, 0.0), (3.0, 0.0)).0;
+        }
+        assert!((last_speed - 3.0).abs() < 0.1, "The drone did not drift with the wind!");
+    }
+
+    #[test]
+    fn settings_line_test() {
+        let settings = DynamicsSettings { mass: 2.0, ..DynamicsSettings::default() };
+        assert_eq!(DynamicsSettings::parse(&settings.to_line()), settings, "The dynamics line was not read back!");
+        assert_eq!(DynamicsSettings::parse("1 -1"), DynamicsSettings::default(), "An invalid drag was accepted!");
+    }
+}
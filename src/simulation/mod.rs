@@ -2,4 +2,6 @@ pub mod traits;
 pub mod movetactics;
 pub mod windtactics;
 
-pub mod virtual_controller;
\ No newline at end of file
+pub mod dynamics;
+pub mod virtual_controller;
+pub mod drone_emulator;
\ No newline at end of file
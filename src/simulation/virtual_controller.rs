@@ -1,4 +1,5 @@
 use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
 
 use rust_drone_follow::traits::Controller;
 
@@ -9,10 +10,14 @@ use rust_drone_follow::utils::PointConverter;
 use rust_drone_follow::models::GeometricPoint;
 
 use opencv::imgproc::{circle, LINE_8};
-use opencv::core::{Mat, CV_8UC3, MatExprTrait, Scalar};
+use opencv::core::{Mat, CV_8UC3, MatExprTrait, Scalar, Size, CV_8U};
 
 use crate::simulation::traits::MoveTactic;
 use crate::simulation::traits::WindTactic;
+use crate::simulation::dynamics::{Dynamics, DynamicsSettings};
+use crate::parrot::connection::{DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT};
+use crate::utils::calibration::{ControllerGains, StepResponse};
+use crate::kalman_filter::FrameTimestamp;
 
 use rand::Rng;
 
@@ -22,30 +27,109 @@ pub struct VirtualController<M: MoveTactic, W: WindTactic> {
     te: TextExporter,
     p_c: PointConverter,
     drone: (f64, f64, f64),
-    drone_v: (f64, f64, f64),
+    dynamics: Dynamics,
+    command: (f64, f64, f64),
     hat: (f64, f64, f64),
     move_tactic: M,
     wind_tactic: W,
     speed: f64,
     skip_frames: u32,
     instability: f64,
+    width: usize,
+    height: usize,
+    gains: ControllerGains,
+    steps: u64,
+    timestamps: FrameTimestamp,
 }
 
+/// Simulated time of a step, the default frame time of the filters: the simulation runs faster than
+/// real time, so its frames are timed by the steps made, which also counts the skipped frames.
+const STEP_TIME: f64 = 1.0 / 30.0;
+
+/// Commands of the simulated calibration steps.
+const CALIBRATION_MOVE: f64 = 0.1;
+const CALIBRATION_TURN: f64 = 0.1;
+
 impl<M: MoveTactic, W: WindTactic> VirtualController<M, W> {
     pub fn new(speed: f64, skip_frames: u32, instability: f64, move_tactic: M, wind_tactic: W, debug: bool) -> VirtualController<M, W> {
         VirtualController {
             print_debug: debug,
-            p_c: PointConverter::new(640, 320),
+            p_c: PointConverter::new(DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT),
             te: TextExporter::new(),
             drone: (0.0, 0.0, 1.57),
-            drone_v: (0.0, 0.0, 0.0),
+            dynamics: Dynamics::new(speed, DynamicsSettings::default()),
+            command: (0.0, 0.0, 0.0),
             hat: (30.0, 45.0, 0.0), //1.57
             speed,
             skip_frames,
             instability,
             move_tactic,
             wind_tactic,
+            width: DEFAULT_VIDEO_WIDTH,
+            height: DEFAULT_VIDEO_HEIGHT,
+            gains: ControllerGains::VIRTUAL,
+            steps: 0,
+            timestamps: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Renders the video in the given size instead of the AR.Drone's 640x360.
+    pub fn with_video_size(mut self, width: usize, height: usize) -> VirtualController<M, W> {
+        self.p_c = PointConverter::new(width, height);
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Moves with the given physical parameters instead of the default ones.
+    pub fn with_dynamics(mut self, settings: DynamicsSettings) -> VirtualController<M, W> {
+        self.dynamics = Dynamics::new(self.speed, settings);
+        self
+    }
+
+    /// Follows with the given gains instead of the uncalibrated ones.
+    pub fn with_gains(mut self, gains: ControllerGains) -> VirtualController<M, W> {
+        self.gains = gains;
+        self
+    }
+
+    pub fn get_gains(&self) -> ControllerGains {
+        self.gains
+    }
+
+    /// The simulated time of the current frame, to be given to the filter.
+    pub fn get_frame_timestamps(&self) -> FrameTimestamp {
+        self.timestamps.clone()
+    }
+
+    /// Simulates a step of moving sideways and then of turning, each after hovering for the same number of frames.
+    /// The velocities are in pixels and radians per frame.
+    pub fn measure_step_responses(&mut self, frames: usize) -> (StepResponse, StepResponse) {
+        let moving = self.measure_step(frames, (CALIBRATION_MOVE, 0.0), |(x, _, _)| x);
+        let turning = self.measure_step(frames, (0.0, CALIBRATION_TURN), |(_, _, a)| a);
+        self.stop();
+        (moving, turning)
+    }
+
+    fn measure_step<F: Fn((f64, f64, f64)) -> f64>(&mut self, frames: usize, (left_right, turn): (f64, f64), coordinate: F) -> StepResponse {
+        let mut response = StepResponse::new(if turn != 0.0 { turn } else { left_right });
+        let mut img = Mat::zeros_size(Size::new(1, 1), CV_8U).unwrap().to_mat().unwrap();
+        self.stop();
+        let mut last = coordinate(self.drone);
+        for i in 0..(2 * frames) {
+            // The commands are given on every frame, the same way as the HatFollower does
+            if i < frames {
+                self.move_all(0.0, 0.0, 0.0, 0.0);
+            } else {
+                self.move_all(left_right, 0.0, 0.0, turn);
+            }
+            self.get_next_frame(&mut img).unwrap();
+            let current = coordinate(self.drone);
+            let time = if i < frames { i as f64 - frames as f64 } else { (i + 1 - frames) as f64 };
+            response.add_sample(time, current - last);
+            last = current;
         }
+        response
     }
 
     pub fn turn_by(&self, (x, y): (f64, f64), a: f64) -> (f64, f64) {
@@ -96,27 +180,27 @@ impl<M: MoveTactic, W: WindTactic> Controller for VirtualController<M, W> {
 
     fn land(&mut self) { }
 
+    /// The commands only set the target tilt and turning speed, the drone gets moving in the next frames.
     fn move_all(&mut self, left_right: f64, back_front: f64, down_up: f64, turn_left_right: f64) {
-        // TODO: Move shouldn't be instant
         if self.print_debug {
             println!("{}, {}, {}, {}", left_right, back_front, down_up, turn_left_right);
             self.te.save_row("commands.txt",
                              format!("{}, {}, {}, {}", left_right, back_front, down_up, turn_left_right));
         }
-        let (old_vx, old_vy, old_va) = self.drone_v;
-        self.drone_v = ((old_vx + left_right) / 2.0, (old_vy + back_front) / 2.0, (old_va + turn_left_right) / 2.0);
+        self.command = (left_right, back_front, turn_left_right);
     }
 
+    /// Hovering: the drone levels out and slows down by the drag.
     fn stop(&mut self) {
-        self.drone_v = (0.0, 0.0, 0.0);
+        self.command = (0.0, 0.0, 0.0);
     }
 
     fn get_video_height(&self) -> usize {
-        360
+        self.height
     }
 
     fn get_video_width(&self) -> usize {
-        640
+        self.width
     }
 
     fn get_next_frame(&mut self, img: &mut Mat) -> opencv::Result<bool> {
@@ -124,16 +208,21 @@ impl<M: MoveTactic, W: WindTactic> Controller for VirtualController<M, W> {
 
         for _i in 0..(1 + self.skip_frames) {
             let (last_x, last_y, last_a) = self.drone;
-            let (v_x, v_y, v_a) = self.drone_v;
-            let (wind_x, wind_y) = self.wind_tactic.get_wind();
-            let (inst_x, inst_y) = (
-                rng.gen_range(- self.instability, self.instability),
-                rng.gen_range(- self.instability, self.instability)
-            );
+            let wind = self.wind_tactic.get_wind();
+            let (d_x, d_y, d_a) = self.dynamics.step(self.command, wind);
+            // gen_range needs a non-empty range
+            let (inst_x, inst_y) = if self.instability > 0.0 {
+                (
+                    rng.gen_range(- self.instability, self.instability),
+                    rng.gen_range(- self.instability, self.instability)
+                )
+            } else {
+                (0.0, 0.0)
+            };
             let (new_x, new_y, new_a) = (
-                last_x as f64 + self.speed * v_x + wind_x + inst_x,
-                last_y as f64 + self.speed * v_y + wind_y + inst_y,
-                last_a + v_a
+                last_x as f64 + d_x + inst_x,
+                last_y as f64 + d_y + inst_y,
+                last_a + d_a
             );
             self.drone = (new_x, new_y, new_a);
 
@@ -141,6 +230,10 @@ impl<M: MoveTactic, W: WindTactic> Controller for VirtualController<M, W> {
             self.hat = self.move_tactic.execute_move(old_hat_x, old_hat_y, old_angle);
 
         }
+        self.steps += 1 + self.skip_frames as u64;
+        if let Ok(mut timestamp) = self.timestamps.lock() {
+            *timestamp = Some(self.steps as f64 * STEP_TIME);
+        }
 
         *img = Mat::ones(self.get_video_height() as i32, self.get_video_width() as i32, CV_8UC3).unwrap().to_mat().unwrap();
 
@@ -151,13 +244,10 @@ impl<M: MoveTactic, W: WindTactic> Controller for VirtualController<M, W> {
     }
 
     fn get_kv(&self) -> f64 {
-        // TODO: NEEDS TESTING
-        0.003
+        self.gains.kv
     }
 
     fn get_ka(&self) -> f64 {
-        // Turning is currently turned off.
-        // TODO: NEEDS TESTING
-        0.01
+        self.gains.ka
     }
 }
\ No newline at end of file
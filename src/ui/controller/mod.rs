@@ -1,3 +1,3 @@
 mod start_follow;
 
-pub use start_follow::start_follow;
\ No newline at end of file
+pub use start_follow::{start_follow, Startup};
\ No newline at end of file
@@ -1,14 +1,29 @@
-use std::{fs, thread};
-use std::sync::mpsc::Sender;
+use std::{fmt, thread};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread::JoinHandle;
 
 use rust_drone_follow::utils::hat_file_reader::read_file;
-use rust_drone_follow::HatFollower;
+use rust_drone_follow::{HatFollower, HatFollowerSettings};
+use rust_drone_follow::models::Hat;
+use rust_drone_follow::traits::{Controller, Filter};
 use rust_drone_follow::detectors::NaiveDetector;
 
-use crate::kalman_filter::KalmanFilter;
+use crate::kalman_filter::{KalmanFilter, KalmanError, FrameTimestamp, SNAPSHOT_FILE};
+use crate::ctrv_filter::CtrvFilter;
+use crate::particle_filter::ParticleFilter;
+use crate::imm_filter::ImmFilter;
+use crate::ui::model::FilterSetting;
+use crate::parrot::parrot_error::ParrotError;
+use crate::parrot::parrot_controller::ParrotController;
+use crate::parrot::battery::BatteryLevel;
+use crate::parrot::altitude_hold::AltitudeLevel;
 
 use crate::utils::file_readers::{read_follow_file, read_kalman_file, read_controller_file};
+use crate::utils::measurement_logger::{MeasurementLogger, FilterDiagnostics};
+use crate::utils::ground_projection::GroundProjection;
+use crate::parrot::camera::CameraModel;
 
 use crate::simulation::virtual_controller::VirtualController;
 use crate::simulation::movetactics::move_squares::MoveSquares;
@@ -16,51 +31,199 @@ use crate::simulation::windtactics::periodic_wind::PeriodicWind;
 use crate::simulation::windtactics::random_wind::RandomWind;
 use crate::simulation::movetactics::stand_still::StandStill;
 
-pub fn start_follow() -> (JoinHandle<()>, Sender<i32>) {
+/// The filter chosen in config.kalman, built before anything is started so that
+/// invalid settings are reported without touching the drone.
+enum ChosenFilter {
+    Kalman(KalmanFilter),
+    Ctrv(CtrvFilter),
+    Particle(ParticleFilter),
+    Imm(ImmFilter),
+}
+
+impl ChosenFilter {
+    /// The filters are built before the controller, the timestamps of its frames are given to them afterwards.
+    fn with_frame_timestamps(self, timestamps: FrameTimestamp) -> ChosenFilter {
+        match self {
+            ChosenFilter::Kalman(f) => ChosenFilter::Kalman(f.with_frame_timestamps(timestamps)),
+            ChosenFilter::Ctrv(f) => ChosenFilter::Ctrv(f.with_frame_timestamps(timestamps)),
+            ChosenFilter::Particle(f) => ChosenFilter::Particle(f.with_frame_timestamps(timestamps)),
+            ChosenFilter::Imm(f) => ChosenFilter::Imm(f.with_frame_timestamps(timestamps)),
+        }
+    }
+}
+
+/// Reasons why following could not be started.
+#[derive(Debug)]
+pub enum StartError {
+    Filter(KalmanError),
+    Drone(ParrotError),
+}
+
+impl From<KalmanError> for StartError {
+    fn from(e: KalmanError) -> StartError {
+        StartError::Filter(e)
+    }
+}
+
+impl From<ParrotError> for StartError {
+    fn from(e: ParrotError) -> StartError {
+        StartError::Drone(e)
+    }
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartError::Filter(e) => write!(f, "invalid Kalman settings: {}", e),
+            StartError::Drone(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Tells once whether the drone started up and took off, with its battery level (None for the simulation).
+pub type Startup = Receiver<Result<Option<BatteryLevel>, StartError>>;
+
+/// Starts the follower in a new thread, returns its handle, the channel that stops it and the one
+/// on which the result of the startup arrives. The drone is started up in the new thread too,
+/// so that the UI (and its emergency buttons) keeps responding during the takeoff.
+pub fn start_follow() -> Result<(JoinHandle<()>, Sender<i32>, Startup), StartError> {
     let settings = read_follow_file("config.follow");
-    let (sigma0, sigma_gain, est_v_loss) = read_kalman_file("config.kalman");
-    let (p_c_opt, v_c_opt) = read_controller_file("config.controller");
+    let (filter_setting, kalman_settings) = read_kalman_file("config.kalman");
+    let filter = match filter_setting {
+        FilterSetting::KalmanFilter => {
+            let mut kalman = KalmanFilter::from_settings(&kalman_settings)?.with_snapshot_file(SNAPSHOT_FILE);
+            if kalman_settings.warm_start {
+                // A missing or outdated snapshot shouldn't stop the drone from following
+                if let Err(e) = kalman.load_snapshot(SNAPSHOT_FILE) {
+                    println!("Starting without the saved filter state: {}", e);
+                }
+            }
+            ChosenFilter::Kalman(kalman)
+        }
+        FilterSetting::CtrvFilter => ChosenFilter::Ctrv(CtrvFilter::from_settings(&kalman_settings)?),
+        FilterSetting::ParticleFilter => ChosenFilter::Particle(ParticleFilter::from_settings(&kalman_settings)?),
+        FilterSetting::ImmFilter => ChosenFilter::Imm(ImmFilter::from_settings(&kalman_settings)?),
+    };
 
-    let (sx, rx) = std::sync::mpsc::channel();
+    let (sx, rx) = channel();
+    let (started_sx, started_rx) = channel();
     let (_, hat) = read_file("config.hat");
-    let join_handle = match p_c_opt {
-        Some(controller) => {
-            thread::spawn(move || {
-                let mut hf = HatFollower::new(
-                    NaiveDetector::new(hat),
-                    controller,
-                    KalmanFilter::new(sigma0, sigma_gain, est_v_loss),
-                    settings,
-                    Some(rx)
-                );
-                hf.run();
-            })
+    let stop_sender = sx.clone();
+    let join_handle = thread::spawn(move || {
+        let (p_c_opt, v_c_opt) = match read_controller_file("config.controller") {
+            Ok(controllers) => controllers,
+            Err(e) => {
+                let _ = started_sx.send(Err(e.into()));
+                return;
+            }
+        };
+        match p_c_opt {
+            Some(controller) => {
+                let mut controller = match take_off(controller) {
+                    Ok(controller) => controller,
+                    Err(e) => {
+                        let _ = started_sx.send(Err(e.into()));
+                        return;
+                    }
+                };
+                controller.set_stop_sender(stop_sender);
+                controller.record_telemetry(&telemetry_filename(&settings));
+                let _ = started_sx.send(Ok(Some(controller.get_battery_level())));
+                let projection = Some((controller.get_camera(), controller.get_video_width(), controller.get_flight_height(),
+                                       controller.get_altitude_level()));
+                let timestamps = controller.get_frame_timestamps();
+                run_with_filter(hat, controller, filter, timestamps, settings, rx, projection);
+            }
+            None => {
+                let _ = started_sx.send(Ok(None));
+                if let Some(controller) = v_c_opt {
+                    let timestamps = controller.get_frame_timestamps();
+                    run_with_filter(hat, controller, filter, timestamps, settings, rx, None);
+                } else {
+                    let controller = VirtualController::new(20.0, 1, 0.01, StandStill::new(), PeriodicWind::new_polar(4.1, 0.3, 80, 500), false);
+                    let timestamps = controller.get_frame_timestamps();
+                    run_with_filter(hat, controller, filter, timestamps, settings, rx, None);
+                }
+            }
         }
+    });
+    Ok((join_handle, sx, started_rx))
+}
+
+/// Started before the HatFollower so that the errors can be shown, it skips the steps already done.
+fn take_off(mut controller: ParrotController) -> Result<ParrotController, ParrotError> {
+    controller.try_init()?;
+    controller.try_takeoff()?;
+    Ok(controller)
+}
+
+/// The navdata is saved next to the video (video_123.mp4 -> navdata_123.csv) if there is one,
+/// otherwise it gets a name of its own.
+fn telemetry_filename(settings: &HatFollowerSettings) -> String {
+    match &settings.save_to_file {
+        Some(video) => Path::new(&video.replace("video", "navdata"))
+            .with_extension("csv")
+            .to_string_lossy()
+            .into_owned(),
         None => {
-            if let Some(controller) = v_c_opt {
-                thread::spawn(move || {
-                    let mut hf = HatFollower::new(
-                        NaiveDetector::new(hat),
-                        controller,
-                        KalmanFilter::new(sigma0, sigma_gain, est_v_loss),
-                        settings,
-                        Some(rx)
-                    );
-                    hf.run();
-                })
-            } else {
-                thread::spawn(move || {
-                    let mut hf = HatFollower::new(
-                        NaiveDetector::new(hat),
-                        VirtualController::new(20.0, 1, 0.01, StandStill::new(), PeriodicWind::new_polar(4.1, 0.3, 80, 500), false),
-                        KalmanFilter::new(sigma0, sigma_gain, est_v_loss),
-                        settings,
-                        Some(rx)
-                    );
-                    hf.run();
-                })
-            }
+            let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            format!("navdata_{}.csv", seconds)
         }
-    };
-    (join_handle, sx)
-}
\ No newline at end of file
+    }
+}
+
+/// The camera (with the video width, the flight height and the altitude of the drone) the measurements
+/// are projected from, None for the simulation, which is always seen from straight above.
+type Projection = Option<(CameraModel, usize, i32, AltitudeLevel)>;
+
+/// The filter (and the measurement log) step on the timestamps of the frames of the controller.
+fn run_with_filter<C>(hat: Hat, controller: C, filter: ChosenFilter, timestamps: FrameTimestamp, settings: HatFollowerSettings,
+                      rx: Receiver<i32>, projection: Projection)
+    where C: Controller + Send + 'static {
+    let t = timestamps.clone();
+    match filter.with_frame_timestamps(timestamps) {
+        ChosenFilter::Kalman(f) => run_logged(hat, controller, f, t, settings, rx, projection),
+        ChosenFilter::Ctrv(f) => run_logged(hat, controller, f, t, settings, rx, projection),
+        ChosenFilter::Particle(f) => run_logged(hat, controller, f, t, settings, rx, projection),
+        ChosenFilter::Imm(f) => run_logged(hat, controller, f, t, settings, rx, projection),
+    }
+}
+
+/// When the commands are saved (Debug mode) the measurements are saved next to them too,
+/// so that the session can be smoothed afterwards.
+fn run_logged<C, F>(hat: Hat, controller: C, filter: F, timestamps: FrameTimestamp, settings: HatFollowerSettings,
+                    rx: Receiver<i32>, projection: Projection)
+    where C: Controller + Send + 'static, F: Filter + FilterDiagnostics + Send + 'static {
+    match settings.save_commands.as_ref().map(|c| c.replace("commands", "measurements")) {
+        Some(filename) => {
+            let logger = MeasurementLogger::new(filter, filename).with_frame_timestamps(timestamps);
+            run_projected(hat, controller, logger, settings, rx, projection)
+        }
+        None => run_projected(hat, controller, filter, settings, rx, projection),
+    }
+}
+
+/// The projection wraps the logger, so that the projected measurements are saved,
+/// the same ones the filter received.
+fn run_projected<C, F>(hat: Hat, controller: C, filter: F, settings: HatFollowerSettings, rx: Receiver<i32>, projection: Projection)
+    where C: Controller + Send + 'static, F: Filter + Send + 'static {
+    match projection {
+        Some((camera, width, flight_height, altitude)) => {
+            let projection = GroundProjection::new(filter, camera, width, flight_height).with_altitude_level(altitude);
+            run_follower(hat, controller, projection, settings, rx)
+        }
+        None => run_follower(hat, controller, filter, settings, rx),
+    }
+}
+
+fn run_follower<C, F>(hat: Hat, controller: C, filter: F, settings: HatFollowerSettings, rx: Receiver<i32>)
+    where C: Controller + Send + 'static, F: Filter + Send + 'static {
+    let mut hf = HatFollower::new(
+        NaiveDetector::new(hat),
+        controller,
+        filter,
+        settings,
+        Some(rx)
+    );
+    hf.run();
+}
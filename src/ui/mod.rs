@@ -1,5 +1,6 @@
 pub mod step;
 pub mod tour;
+pub mod timer;
 
 // Phases
 pub mod view;
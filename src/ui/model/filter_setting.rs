@@ -0,0 +1,29 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterSetting {
+    KalmanFilter,
+    CtrvFilter,
+    ParticleFilter,
+    ImmFilter,
+}
+
+impl FilterSetting {
+    pub fn all() -> [FilterSetting; 4] {
+        [
+            FilterSetting::KalmanFilter,
+            FilterSetting::CtrvFilter,
+            FilterSetting::ParticleFilter,
+            FilterSetting::ImmFilter,
+        ]
+    }
+}
+
+impl From<FilterSetting> for String {
+    fn from(setting: FilterSetting) -> String {
+        String::from(match setting {
+            FilterSetting::KalmanFilter   => "KalmanFilter",
+            FilterSetting::CtrvFilter     => "CtrvFilter",
+            FilterSetting::ParticleFilter => "ParticleFilter",
+            FilterSetting::ImmFilter      => "ImmFilter",
+        })
+    }
+}
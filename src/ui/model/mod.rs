@@ -2,6 +2,7 @@ mod default_setting;
 mod controller_setting;
 mod wind_setting;
 mod person_setting;
+mod filter_setting;
 
 mod step_message;
 mod tour_message;
@@ -11,6 +12,7 @@ pub use default_setting::DefaultSetting;
 pub use controller_setting::ControllerSetting;
 pub use wind_setting::WindSetting;
 pub use person_setting::PersonSetting;
+pub use filter_setting::FilterSetting;
 
 pub use step_message::StepMessage;
 pub use tour_message::TourMessage;
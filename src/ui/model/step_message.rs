@@ -1,10 +1,20 @@
-use crate::ui::model::{DefaultSetting, ControllerSetting, WindSetting, PersonSetting};
+use crate::ui::model::{DefaultSetting, ControllerSetting, WindSetting, PersonSetting, FilterSetting};
 
 #[derive(Debug, Clone)]
 pub enum StepMessage {
     SetController(ControllerSetting),
     SetWind(WindSetting),
     SetPerson(PersonSetting),
+    DroneAddress(String),
+    VideoPort(String),
+    VideoUrl(String),
+    BatteryWarning(String),
+    BatteryCritical(String),
+    AltitudeGains(String),
+    VideoSize(String),
+    FollowGains(String),
+    DroneCamera(String),
+    Dynamics(String),
     SaveController,
     Takeoff,
     TakePicture,
@@ -17,9 +27,20 @@ pub enum StepMessage {
     HighB(String),
     Size(String),
     SaveHat,
+    SetFilter(FilterSetting),
     Sigma0(String),
     SigmaGain(String),
     VLose(String),
+    MaxCoast(String),
+    FrameTime(String),
+    GateThreshold(String),
+    WarmStart(bool),
+    ParticleCount(String),
+    Seed(String),
+    ToMoving(String),
+    ToStationary(String),
+    ProcessNoise(String),
+    MeasurementNoise(String),
     SaveKalman,
     SettingChanged(DefaultSetting),
     MinChange(String),
@@ -27,4 +48,7 @@ pub enum StepMessage {
     SaveFollower,
     Start,
     Stop,
+    EmergencyLand,
+    CutMotors,
+    Tick,
 }
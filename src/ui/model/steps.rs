@@ -1,7 +1,7 @@
 use iced::{button, text_input, Element};
 
 use crate::ui::step::Step;
-use crate::ui::model::{StepMessage, WindSetting, PersonSetting};
+use crate::ui::model::{StepMessage, WindSetting, PersonSetting, FilterSetting};
 
 pub struct Steps {
     steps: Vec<Step>,
@@ -17,6 +17,26 @@ impl Steps {
                     cs: None,
                     ws: Some(WindSetting::PeriodicWind),
                     ps: Some(PersonSetting::StandStill),
+                    address: "".to_string(),
+                    video_port: "".to_string(),
+                    video_url: "".to_string(),
+                    battery_warning: "".to_string(),
+                    battery_critical: "".to_string(),
+                    altitude_gains: "".to_string(),
+                    video_size: "".to_string(),
+                    follow_gains: "".to_string(),
+                    camera: "".to_string(),
+                    dynamics: "".to_string(),
+                    address_input: text_input::State::new(),
+                    port_input: text_input::State::new(),
+                    url_input: text_input::State::new(),
+                    warning_input: text_input::State::new(),
+                    critical_input: text_input::State::new(),
+                    gains_input: text_input::State::new(),
+                    size_input: text_input::State::new(),
+                    follow_gains_input: text_input::State::new(),
+                    camera_input: text_input::State::new(),
+                    dynamics_input: text_input::State::new(),
                     save_controller: button::State::new(),
                 },
                 Step::GetPicture {
@@ -24,8 +44,13 @@ impl Steps {
                     takeoff_state: button::State::new(),
                     picture_state: button::State::new(),
                     land_state: button::State::new(),
+                    emergency_state: button::State::new(),
+                    cutoff_state: button::State::new(),
+                    takeoff: None,
                     sender_channel: None,
-                    join_handle: None
+                    join_handle: None,
+                    stopping: None,
+                    error: "".to_string()
                 },
                 Step::SetHatColor {
                     hat: None,
@@ -47,12 +72,33 @@ impl Steps {
                     size_input: text_input::State::new()
                 },
                 Step::SetKalmanSettings {
+                    filter: Some(FilterSetting::KalmanFilter),
                     sigma_0: "".to_string(),
                     sigma_gain: "".to_string(),
                     est_v_loss: "".to_string(),
+                    max_coast: "".to_string(),
+                    frame_time: "".to_string(),
+                    gate_threshold: "".to_string(),
+                    warm_start: false,
+                    particle_count: "".to_string(),
+                    seed: "".to_string(),
+                    to_moving: "".to_string(),
+                    to_stationary: "".to_string(),
+                    process_noise: "".to_string(),
+                    measurement_noise: "".to_string(),
+                    kalman_error: "".to_string(),
                     s0_input: text_input::State::new(),
                     sg_input: text_input::State::new(),
                     vl_input: text_input::State::new(),
+                    mcf_input: text_input::State::new(),
+                    ft_input: text_input::State::new(),
+                    gt_input: text_input::State::new(),
+                    pc_input: text_input::State::new(),
+                    seed_input: text_input::State::new(),
+                    tm_input: text_input::State::new(),
+                    ts_input: text_input::State::new(),
+                    pn_input: text_input::State::new(),
+                    mn_input: text_input::State::new(),
                     save_kalman: button::State::new()
                 },
                 Step::SetFollowerSettings {
@@ -66,8 +112,14 @@ impl Steps {
                 Step::Run {
                     sender_channel: None,
                     join_handle: None,
+                    stopping: None,
+                    startup: None,
                     start_button: button::State::new(),
-                    stop_button: button::State::new()
+                    stop_button: button::State::new(),
+                    emergency_button: button::State::new(),
+                    cutoff_button: button::State::new(),
+                    battery: None,
+                    error: "".to_string()
                 }
             ],
             current: 0,
@@ -119,4 +171,8 @@ impl Steps {
         self.steps[self.current].title()
     }
 
+    pub fn needs_ticks(&self) -> bool {
+        self.steps[self.current].needs_ticks()
+    }
+
 }
@@ -1,6 +1,6 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{thread};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
 use std::thread::JoinHandle;
 
 use iced::{button, text_input, Element, Column};
@@ -17,12 +17,25 @@ use super::view::set_kalman_settings;
 use super::view::set_follower_settings;
 use super::view::run;
 
-use crate::ui::model::{StepMessage, DefaultSetting, ControllerSetting, WindSetting, PersonSetting};
+use crate::ui::model::{StepMessage, DefaultSetting, ControllerSetting, WindSetting, PersonSetting, FilterSetting};
 
 use crate::utils::picture_recorder::picture_recorder;
 use crate::utils::picture_funcs::{get_color_from_strings, mask_image};
+use crate::utils::file_readers::{parse_kalman_settings, read_connection, read_battery_settings, read_altitude_settings, read_camera_model, parse_video_size};
+
+use crate::kalman_filter::KalmanFilter;
+use crate::imm_filter::ImmFilter;
 
 use crate::parrot::parrot_controller::ParrotController;
+use crate::parrot::connection::ParrotConnection;
+use crate::parrot::parrot_error::ParrotError;
+use crate::parrot::battery::{BatteryLevel, BatterySettings};
+use crate::parrot::safety;
+use crate::parrot::altitude_hold::AltitudeSettings;
+use crate::utils::calibration::ControllerGains;
+use crate::parrot::camera::CameraModel;
+use crate::simulation::dynamics::DynamicsSettings;
+use crate::ui::controller::Startup;
 
 pub enum Step {
     Welcome,
@@ -30,15 +43,42 @@ pub enum Step {
         cs: Option<ControllerSetting>,
         ws: Option<WindSetting>,
         ps: Option<PersonSetting>,
+        address: String,
+        video_port: String,
+        video_url: String,
+        battery_warning: String,
+        battery_critical: String,
+        altitude_gains: String,
+        video_size: String,
+        follow_gains: String,
+        camera: String,
+        dynamics: String,
+        address_input: text_input::State,
+        port_input: text_input::State,
+        url_input: text_input::State,
+        warning_input: text_input::State,
+        critical_input: text_input::State,
+        gains_input: text_input::State,
+        size_input: text_input::State,
+        follow_gains_input: text_input::State,
+        camera_input: text_input::State,
+        dynamics_input: text_input::State,
         save_controller: button::State,
     },
     GetPicture {
         drone: Option<ParrotController>,
+        /// The drone taking off in the background, with the URL of its video
+        takeoff: Option<Receiver<Result<(ParrotController, String), ParrotError>>>,
         takeoff_state: button::State,
         picture_state: button::State,
         land_state: button::State,
+        emergency_state: button::State,
+        cutoff_state: button::State,
         sender_channel: Option<Sender<i32>>,
         join_handle: Option<JoinHandle<()>>,
+        /// The picture thread that was told to stop, joined once it ended
+        stopping: Option<JoinHandle<()>>,
+        error: String,
     },
     SetHatColor {
         hat: Option<Hat>,
@@ -60,12 +100,33 @@ pub enum Step {
         size_input: text_input::State,
     },
     SetKalmanSettings {
+        filter: Option<FilterSetting>,
         sigma_0: String,
         sigma_gain: String,
         est_v_loss: String,
+        max_coast: String,
+        frame_time: String,
+        gate_threshold: String,
+        warm_start: bool,
+        particle_count: String,
+        seed: String,
+        to_moving: String,
+        to_stationary: String,
+        process_noise: String,
+        measurement_noise: String,
+        kalman_error: String,
         s0_input: text_input::State,
         sg_input: text_input::State,
         vl_input: text_input::State,
+        mcf_input: text_input::State,
+        ft_input: text_input::State,
+        gt_input: text_input::State,
+        pc_input: text_input::State,
+        seed_input: text_input::State,
+        tm_input: text_input::State,
+        ts_input: text_input::State,
+        pn_input: text_input::State,
+        mn_input: text_input::State,
         save_kalman: button::State,
     },
     SetFollowerSettings {
@@ -79,13 +140,86 @@ pub enum Step {
     Run {
         sender_channel: Option<Sender<i32>>,
         join_handle: Option<JoinHandle<()>>,
+        /// The follower that was told to stop, joined once it landed
+        stopping: Option<JoinHandle<()>>,
+        startup: Option<Startup>,
         start_button: button::State,
         stop_button: button::State,
+        emergency_button: button::State,
+        cutoff_button: button::State,
+        battery: Option<BatteryLevel>,
+        error: String,
     }
 }
 
 impl<'a> Step {
+    /// The drone is started up in the background, so that the UI keeps responding. Iced only updates
+    /// on a message, so whether it is done is checked on every one of them (e.g. the Tick of the timer).
+    fn poll_startup(&mut self) {
+        match self {
+            Step::GetPicture {drone, takeoff, sender_channel, join_handle, error, ..} => {
+                let result = match takeoff.as_ref().map(|rx| rx.try_recv()) {
+                    Some(Ok(result)) => result,
+                    Some(Err(TryRecvError::Disconnected)) => Err(ParrotError::NotConnected),
+                    Some(Err(TryRecvError::Empty)) | None => return,
+                };
+                *takeoff = None;
+                match result {
+                    Ok((controller, opencv_url)) => {
+                        *error = String::new();
+                        let (sx, rx) = channel();
+                        *join_handle = Some(thread::spawn(move || picture_recorder(rx, opencv_url)));
+                        *sender_channel = Some(sx);
+                        drone.replace(controller);
+                    }
+                    Err(e) => {
+                        *error = format!("Could not take off: {}", e);
+                    }
+                }
+            }
+            Step::Run {startup, join_handle, stopping, sender_channel, battery, error, ..} => {
+                let result = match startup.as_ref().map(|rx| rx.try_recv()) {
+                    Some(Ok(result)) => result,
+                    Some(Err(TryRecvError::Disconnected)) => Err(ParrotError::NotConnected.into()),
+                    Some(Err(TryRecvError::Empty)) | None => return,
+                };
+                *startup = None;
+                match result {
+                    Ok(battery_level) => {
+                        *error = String::new();
+                        *battery = battery_level;
+                    }
+                    Err(e) => {
+                        *error = format!("Could not start following: {}", e);
+                        // The follower thread ends after a failed startup
+                        sender_channel.take();
+                        *stopping = join_handle.take();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The stopped threads are joined once they ended, the UI thread never waits for them
+    /// (the follower takes LANDING_TIME to stop).
+    fn poll_stopped(&mut self) {
+        let (stopping, error, name) = match self {
+            Step::GetPicture {stopping, error, ..} => (stopping, error, "picture"),
+            Step::Run {stopping, error, ..} => (stopping, error, "follower"),
+            _ => return,
+        };
+        if !stopping.as_ref().map(|handle| handle.is_finished()).unwrap_or(false) {
+            return;
+        }
+        if let Some(Err(_)) = stopping.take().map(|handle| handle.join()) {
+            *error = format!("The {} thread stopped with an error, see the console.", name);
+        }
+    }
+
     pub fn update(&mut self, msg: StepMessage) {
+        self.poll_startup();
+        self.poll_stopped();
         match msg {
             StepMessage::SetController(controller) => {
                 if let Step::SetController {cs, ..} = self {
@@ -102,25 +236,110 @@ impl<'a> Step {
                     *ps = Some(person);
                 }
             },
+            StepMessage::DroneAddress(val) => {
+                if let Step::SetController {address, ..} = self {
+                    *address = val;
+                }
+            }
+            StepMessage::VideoPort(val) => {
+                if let Step::SetController {video_port, ..} = self {
+                    *video_port = val;
+                }
+            }
+            StepMessage::VideoUrl(val) => {
+                if let Step::SetController {video_url, ..} = self {
+                    *video_url = val;
+                }
+            }
+            StepMessage::BatteryWarning(val) => {
+                if let Step::SetController {battery_warning, ..} = self {
+                    *battery_warning = val;
+                }
+            }
+            StepMessage::BatteryCritical(val) => {
+                if let Step::SetController {battery_critical, ..} = self {
+                    *battery_critical = val;
+                }
+            }
+            StepMessage::AltitudeGains(val) => {
+                if let Step::SetController {altitude_gains, ..} = self {
+                    *altitude_gains = val;
+                }
+            }
+            StepMessage::VideoSize(val) => {
+                if let Step::SetController {video_size, ..} = self {
+                    *video_size = val;
+                }
+            }
+            StepMessage::FollowGains(val) => {
+                if let Step::SetController {follow_gains, ..} = self {
+                    *follow_gains = val;
+                }
+            }
+            StepMessage::DroneCamera(val) => {
+                if let Step::SetController {camera, ..} = self {
+                    *camera = val;
+                }
+            }
+            StepMessage::Dynamics(val) => {
+                if let Step::SetController {dynamics, ..} = self {
+                    *dynamics = val;
+                }
+            }
             StepMessage::SaveController => {
-                if let Step::SetController {cs, ws, ps, ..} = self {
+                if let Step::SetController {cs, ws, ps, address, video_port, video_url, battery_warning, battery_critical, altitude_gains, video_size, follow_gains, camera, dynamics, ..} = self {
+                    // Empty fields fall back to the defaults
+                    let default = ParrotConnection::default();
+                    let connection = ParrotConnection {
+                        address: if address.trim().is_empty() { default.address } else { String::from(address.trim()) },
+                        video_port: video_port.trim().parse::<u16>().unwrap_or(default.video_port),
+                        video_url: if video_url.trim().is_empty() { None } else { Some(String::from(video_url.trim())) },
+                    };
+                    let default_battery = BatterySettings::default();
+                    let battery = BatterySettings {
+                        warning_level: battery_warning.trim().parse::<u32>().unwrap_or(default_battery.warning_level),
+                        critical_level: battery_critical.trim().parse::<u32>().unwrap_or(default_battery.critical_level),
+                    };
                     let mut text_exporter = TextExporter::new();
                     text_exporter.save_row("config.controller", format!("{}\n", String::from(cs.unwrap())));
                     text_exporter.save_row("config.controller", format!("{}\n", String::from(ws.unwrap())));
-                    text_exporter.save_row("config.controller", format!("{}", String::from(ps.unwrap())));
+                    text_exporter.save_row("config.controller", format!("{}\n", String::from(ps.unwrap())));
+                    text_exporter.save_row("config.controller", format!("{}\n", connection.to_line()));
+                    text_exporter.save_row("config.controller", format!("{}\n", battery.to_line()));
+                    text_exporter.save_row("config.controller", format!("{}\n", AltitudeSettings::parse(altitude_gains).to_line()));
+                    let (width, height) = parse_video_size(video_size);
+                    text_exporter.save_row("config.controller", format!("{} {}\n", width, height));
+                    let default_gains = if *cs == Some(ControllerSetting::ParrotController) { ControllerGains::PARROT } else { ControllerGains::VIRTUAL };
+                    text_exporter.save_row("config.controller", format!("{}\n", ControllerGains::parse(follow_gains, default_gains).to_line()));
+                    text_exporter.save_row("config.controller", format!("{}\n", CameraModel::parse(camera).to_line()));
+                    text_exporter.save_row("config.controller", DynamicsSettings::parse(dynamics).to_line());
                 }
             }
             StepMessage::Takeoff => {
-                if let Step::GetPicture {drone, sender_channel, join_handle, ..} = self {
-                    if drone.is_none() {
-                        let mut controller = ParrotController::new(300, false);
-                        controller.init();
-                        controller.takeoff();
-                        let (sx, rx) = std::sync::mpsc::channel();
-                        let opencv_url = String::from("tcp://192.168.1.1:5555");
-                        *join_handle = Some(thread::spawn(move || picture_recorder(rx, opencv_url)));
-                        *sender_channel = Some(sx);
-                        drone.replace(controller);
+                if let Step::GetPicture {drone, takeoff, error, ..} = self {
+                    if drone.is_none() && takeoff.is_none() {
+                        let (sx, rx) = channel();
+                        thread::spawn(move || {
+                            let connection = read_connection("config.controller");
+                            let started = ParrotController::new(
+                                    300,
+                                    false,
+                                    &connection,
+                                    read_battery_settings("config.controller"),
+                                    read_altitude_settings("config.controller"))
+                                // The hat is photographed with the camera that will follow it
+                                .map(|controller| controller.with_camera(read_camera_model("config.controller")))
+                                .and_then(|mut controller| {
+                                    controller.try_init()?;
+                                    controller.try_takeoff()?;
+                                    Ok(controller)
+                                });
+                            // If Land was pushed during the takeoff nobody receives the controller,
+                            // it is dropped here, which lands the drone
+                            let _ = sx.send(started.map(|controller| (controller, connection.video_url())));
+                        });
+                        *takeoff = Some(rx);
+                        *error = String::from("Taking off...");
                     }
                 }
             }
@@ -134,14 +353,17 @@ impl<'a> Step {
                 }
             }
             StepMessage::Land => {
-                if let Step::GetPicture {drone, sender_channel, join_handle, ..} = self {
-                    let controller_option = drone.take();
-                    if controller_option.is_some() {
-                        let mut controller = controller_option.unwrap();
-                        let sx = sender_channel.take().unwrap();
-                        let picture_thread = join_handle.take().unwrap();
-                        sx.send(0).unwrap();
-                        picture_thread.join().unwrap();
+                if let Step::GetPicture {drone, takeoff, sender_channel, join_handle, stopping, error, ..} = self {
+                    // The drone taking off is landed by the thread starting it up
+                    if takeoff.take().is_some() {
+                        *error = String::from("Landing after the takeoff.");
+                    }
+                    if let Some(mut controller) = drone.take() {
+                        // The picture thread may have stopped already (e.g. its window was closed)
+                        if let Some(sx) = sender_channel.take() {
+                            let _ = sx.send(0);
+                        }
+                        *stopping = join_handle.take();
                         controller.land();
                     }
                 }
@@ -207,6 +429,12 @@ impl<'a> Step {
                 }
             }
 
+            StepMessage::SetFilter(val) => {
+                if let Step::SetKalmanSettings {filter, ..} = self {
+                    *filter = Some(val);
+                }
+            }
+
             StepMessage::Sigma0(val) => {
                 if let Step::SetKalmanSettings {sigma_0, ..} = self {
                     *sigma_0 = val;
@@ -225,10 +453,89 @@ impl<'a> Step {
                 }
             }
 
+            StepMessage::MaxCoast(val) => {
+                if let Step::SetKalmanSettings {max_coast, ..} = self {
+                    *max_coast = val;
+                }
+            }
+
+            StepMessage::FrameTime(val) => {
+                if let Step::SetKalmanSettings {frame_time, ..} = self {
+                    *frame_time = val;
+                }
+            }
+
+            StepMessage::GateThreshold(val) => {
+                if let Step::SetKalmanSettings {gate_threshold, ..} = self {
+                    *gate_threshold = val;
+                }
+            }
+
+            StepMessage::WarmStart(val) => {
+                if let Step::SetKalmanSettings {warm_start, ..} = self {
+                    *warm_start = val;
+                }
+            }
+
+            StepMessage::ParticleCount(val) => {
+                if let Step::SetKalmanSettings {particle_count, ..} = self {
+                    *particle_count = val;
+                }
+            }
+
+            StepMessage::Seed(val) => {
+                if let Step::SetKalmanSettings {seed, ..} = self {
+                    *seed = val;
+                }
+            }
+
+            StepMessage::ToMoving(val) => {
+                if let Step::SetKalmanSettings {to_moving, ..} = self {
+                    *to_moving = val;
+                }
+            }
+
+            StepMessage::ToStationary(val) => {
+                if let Step::SetKalmanSettings {to_stationary, ..} = self {
+                    *to_stationary = val;
+                }
+            }
+
+            StepMessage::ProcessNoise(val) => {
+                if let Step::SetKalmanSettings {process_noise, ..} = self {
+                    *process_noise = val;
+                }
+            }
+
+            StepMessage::MeasurementNoise(val) => {
+                if let Step::SetKalmanSettings {measurement_noise, ..} = self {
+                    *measurement_noise = val;
+                }
+            }
+
             StepMessage::SaveKalman => {
-                if let Step::SetKalmanSettings {sigma_0, sigma_gain, est_v_loss, ..} = self {
-                    let mut text_exporter = TextExporter::new();
-                    text_exporter.save_row("config.kalman", format!("{} {} {}", sigma_0, sigma_gain, est_v_loss));
+                if let Step::SetKalmanSettings {filter, sigma_0, sigma_gain, est_v_loss, max_coast, frame_time, gate_threshold, warm_start, particle_count, seed, to_moving, to_stationary, process_noise, measurement_noise, kalman_error, ..} = self {
+                    let filter_str = match filter.unwrap_or(FilterSetting::KalmanFilter) {
+                        FilterSetting::ParticleFilter => format!("ParticleFilter {} {}", particle_count, seed),
+                        FilterSetting::ImmFilter => format!("ImmFilter {} {}", to_moving, to_stationary),
+                        other => String::from(other),
+                    };
+                    let content = format!("{} {} {} {} {} {} {}\n{}\n{}\n{}", sigma_0, sigma_gain, est_v_loss, max_coast, frame_time, gate_threshold, warm_start, process_noise, measurement_noise, filter_str);
+                    let settings = parse_kalman_settings(content.as_str());
+                    let checked = match filter {
+                        Some(FilterSetting::ImmFilter) => ImmFilter::from_settings(&settings).map(|_| ()),
+                        _ => KalmanFilter::from_settings(&settings).map(|_| ()),
+                    };
+                    match checked {
+                        Ok(_) => {
+                            *kalman_error = String::new();
+                            let mut text_exporter = TextExporter::new();
+                            text_exporter.save_row("config.kalman", content);
+                        }
+                        Err(e) => {
+                            *kalman_error = format!("{}", e);
+                        }
+                    }
                 }
             }
 
@@ -263,25 +570,54 @@ impl<'a> Step {
             }
 
             StepMessage::Start => {
-                if let Step::Run {join_handle, sender_channel, ..} = self {
-                    if join_handle.is_none() {
-                        let (handle, sx) = crate::ui::controller::start_follow();
-                        *join_handle = Some(handle);
-                        *sender_channel = Some(sx);
+                if let Step::Run {join_handle, stopping, sender_channel, startup, error, ..} = self {
+                    if stopping.is_some() {
+                        *error = String::from("The drone is still landing, push Start again after it landed.");
+                    } else if join_handle.is_none() {
+                        match crate::ui::controller::start_follow() {
+                            Ok((handle, sx, started)) => {
+                                *error = String::from("Starting up...");
+                                *startup = Some(started);
+                                *join_handle = Some(handle);
+                                *sender_channel = Some(sx);
+                            }
+                            Err(e) => {
+                                *error = format!("Could not start following: {}", e);
+                            }
+                        }
                     }
                 }
             }
 
             StepMessage::Stop => {
-                if let Step::Run {join_handle, sender_channel, ..} = self {
-                    if join_handle.is_some() {
-                        let follower_thread = join_handle.take().unwrap();
-                        let sx = sender_channel.take().unwrap();
-                        sx.send(0).unwrap();
-                        follower_thread.join().unwrap();
+                if let Step::Run {join_handle, stopping, sender_channel, startup, ..} = self {
+                    // A follower still starting up stops (and lands) as soon as it took off
+                    startup.take();
+                    if let Some(follower_thread) = join_handle.take() {
+                        // The follower may have stopped already (e.g. landed on low battery)
+                        if let Some(sx) = sender_channel.take() {
+                            let _ = sx.send(0);
+                        }
+                        *stopping = Some(follower_thread);
                     }
                 }
             }
+
+            StepMessage::EmergencyLand => {
+                if let Step::GetPicture {drone, ..} = self {
+                    if let Some(controller) = drone.as_mut() {
+                        controller.emergency_land();
+                    }
+                }
+                safety::request_emergency_landing();
+            }
+
+            StepMessage::CutMotors => {
+                safety::cut_motors();
+            }
+
+            // Nothing to do, the startup is checked and the view shows the latest battery level after every message
+            StepMessage::Tick => {}
         }
     }
 
@@ -297,6 +633,16 @@ impl<'a> Step {
         }
     }
 
+    /// Whether something runs in the background that the view has to follow: a takeoff, a follower
+    /// (with its battery level) or a thread that is being stopped.
+    pub fn needs_ticks(&self) -> bool {
+        match self {
+            Step::GetPicture {takeoff, stopping, ..} => takeoff.is_some() || stopping.is_some(),
+            Step::Run {join_handle, stopping, startup, ..} => join_handle.is_some() || stopping.is_some() || startup.is_some(),
+            _ => false,
+        }
+    }
+
     pub fn can_continue(&self) -> bool {
         match self {
             Step::Run {..} => false,
@@ -311,11 +657,17 @@ impl<'a> Step {
     pub fn view(&mut self) -> Element<StepMessage> {
         match self {
             Step::Welcome => welcome(Self::container()),
-            Step::SetController { cs, ws, ps, save_controller } => {
-                set_controller_settings(Self::container(), save_controller, (cs.clone(), ws.clone(), ps.clone()))
+            Step::SetController { cs, ws, ps, address, video_port, video_url, battery_warning, battery_critical, altitude_gains, video_size, follow_gains, camera, dynamics, address_input, port_input, url_input, warning_input, critical_input, gains_input, size_input, follow_gains_input, camera_input, dynamics_input, save_controller } => {
+                set_controller_settings(
+                    Self::container(),
+                    save_controller,
+                    (cs.clone(), ws.clone(), ps.clone()),
+                    (address, video_port, video_url, battery_warning, battery_critical, altitude_gains, video_size, follow_gains, camera, dynamics),
+                    (address_input, port_input, url_input, warning_input, critical_input, gains_input, size_input, follow_gains_input, camera_input, dynamics_input)
+                )
             },
-            Step::GetPicture { takeoff_state, picture_state, land_state, .. } => {
-                get_picture(Self::container(), (takeoff_state, picture_state, land_state))
+            Step::GetPicture { takeoff_state, picture_state, land_state, emergency_state, cutoff_state, error, .. } => {
+                get_picture(Self::container(), (takeoff_state, picture_state, land_state, emergency_state, cutoff_state), error)
             }
             Step::SetHatColor {hls, has, hbs, lls, las, lbs, l_high_input, a_high_input, b_high_input, l_low_input, a_low_input, b_low_input, save_hat, masked_img, size, size_input, ..} => {
                 set_hat_color(
@@ -325,11 +677,14 @@ impl<'a> Step {
                     masked_img
                 )
             }
-            Step::SetKalmanSettings {sigma_0, sigma_gain, est_v_loss, s0_input, sg_input, vl_input, save_kalman} => {
+            Step::SetKalmanSettings {filter, sigma_0, sigma_gain, est_v_loss, max_coast, frame_time, gate_threshold, warm_start, particle_count, seed, to_moving, to_stationary, process_noise, measurement_noise, kalman_error, s0_input, sg_input, vl_input, mcf_input, ft_input, gt_input, pc_input, seed_input, tm_input, ts_input, pn_input, mn_input, save_kalman} => {
                 set_kalman_settings(
                     Self::container(),
-                    (sigma_0, sigma_gain, est_v_loss),
-                    (s0_input, sg_input, vl_input, save_kalman)
+                    (sigma_0, sigma_gain, est_v_loss, max_coast, frame_time, gate_threshold, particle_count, seed, to_moving, to_stationary, process_noise, measurement_noise),
+                    (s0_input, sg_input, vl_input, mcf_input, ft_input, gt_input, pc_input, seed_input, tm_input, ts_input, pn_input, mn_input, save_kalman),
+                    filter.clone(),
+                    *warm_start,
+                    kalman_error
                 )
             }
             Step::SetFollowerSettings {min_change, center_threshold, mc_input, ct_input, save_follower, setting} => {
@@ -340,10 +695,13 @@ impl<'a> Step {
                     setting.clone()
                 )
             }
-            Step::Run {start_button, stop_button, ..} => {
+            Step::Run {start_button, stop_button, emergency_button, cutoff_button, battery, error, ..} => {
+                let battery_level = battery.as_ref().and_then(|b| b.lock().ok().and_then(|l| *l));
                 run(
                     Self::container(),
-                    (start_button, stop_button)
+                    (start_button, stop_button, emergency_button, cutoff_button),
+                    battery_level,
+                    error
                 )
             }
         }.into()
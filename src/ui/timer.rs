@@ -0,0 +1,39 @@
+use std::hash::{Hash, Hasher};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iced::Subscription;
+use iced::futures::channel::mpsc;
+use iced::futures::stream::{BoxStream, StreamExt};
+
+/// Ticks with the current time every `duration`, for as long as the subscription is kept.
+/// Iced only redraws on a message, so the state of the background threads is polled this way.
+pub fn every(duration: Duration) -> Subscription<Instant> {
+    Subscription::from_recipe(Every(duration))
+}
+
+struct Every(Duration);
+
+impl<H, I> iced_native::subscription::Recipe<H, I> for Every where H: Hasher {
+    type Output = Instant;
+
+    fn hash(&self, state: &mut H) {
+        std::any::TypeId::of::<Self>().hash(state);
+        self.0.hash(state);
+    }
+
+    fn stream(self: Box<Self>, _input: BoxStream<'static, I>) -> BoxStream<'static, Instant> {
+        let (sx, rx) = mpsc::unbounded();
+        let duration = self.0;
+        // The thread ends with the first tick after the subscription was dropped
+        thread::spawn(move || {
+            loop {
+                thread::sleep(duration);
+                if sx.unbounded_send(Instant::now()).is_err() {
+                    break;
+                }
+            }
+        });
+        rx.boxed()
+    }
+}
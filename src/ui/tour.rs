@@ -1,6 +1,12 @@
-use iced::{scrollable, button, Sandbox, Element, Row, Space, Length, Column, Scrollable, Container, Button, Text};
+use std::time::Duration;
 
-use crate::ui::model::{TourMessage, Steps};
+use iced::{scrollable, button, executor, Application, Command, Subscription, Element, Row, Space, Length, Column, Scrollable, Container, Button, Text};
+
+use crate::ui::model::{TourMessage, StepMessage, Steps};
+use crate::ui::timer;
+
+/// How often the view follows the threads running in the background (e.g. the battery level).
+const TICK_INTERVAL: Duration = Duration::from_millis(500);
 
 pub struct Tour {
     steps: Steps,
@@ -11,25 +17,27 @@ pub struct Tour {
     end_button: button::State,
 }
 
-impl Sandbox for Tour {
+impl Application for Tour {
+    type Executor = executor::Default;
     type Message = TourMessage;
+    type Flags = ();
 
-    fn new() -> Self {
-       Tour {
+    fn new(_flags: ()) -> (Self, Command<Self::Message>) {
+       (Tour {
            steps: Steps::new(),
            scroll: scrollable::State::new(),
            begin_button: button::State::new(),
            back_button: button::State::new(),
            next_button: button::State::new(),
            end_button: button::State::new()
-       }
+       }, Command::none())
     }
 
     fn title(&self) -> String {
         format!("{}", self.steps.title())
     }
 
-    fn update(&mut self, message: Self::Message) {
+    fn update(&mut self, message: Self::Message) -> Command<Self::Message> {
         match message {
             TourMessage::StartPressed => {
                 self.steps.go_to_start();
@@ -47,6 +55,15 @@ impl Sandbox for Tour {
                 self.steps.update(msg);
             }
         }
+        Command::none()
+    }
+
+    fn subscription(&self) -> Subscription<Self::Message> {
+        if self.steps.needs_ticks() {
+            timer::every(TICK_INTERVAL).map(|_| TourMessage::StepMessage(StepMessage::Tick))
+        } else {
+            Subscription::none()
+        }
     }
 
     fn view(&mut self) -> Element<'_, Self::Message> {
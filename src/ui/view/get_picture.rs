@@ -3,8 +3,8 @@ use iced::button::State as ButtonState;
 
 use crate::ui::model::StepMessage;
 
-pub fn get_picture<'a>(container: Column<'a, StepMessage>, (ts, ps, ls): (&'a mut ButtonState, &'a mut ButtonState, &'a mut ButtonState)) -> Column<'a, StepMessage> {
-    container
+pub fn get_picture<'a>(container: Column<'a, StepMessage>, (ts, ps, ls, es, cs): (&'a mut ButtonState, &'a mut ButtonState, &'a mut ButtonState, &'a mut ButtonState, &'a mut ButtonState), error: &str) -> Column<'a, StepMessage> {
+    let container = container
         .align_items(Align::Center)
         .push(Column::new()
             .align_items(Align::Start)
@@ -19,4 +19,13 @@ pub fn get_picture<'a>(container: Column<'a, StepMessage>, (ts, ps, ls): (&'a mu
             .push(Button::new(ts, Text::new("Takeoff")).padding(15).on_press(StepMessage::Takeoff))
             .push(Button::new(ps, Text::new("Take Picture")).padding(15).on_press(StepMessage::TakePicture))
             .push(Button::new(ls, Text::new("Land")).padding(15).on_press(StepMessage::Land)))
+        .push(Row::new()
+            .spacing(10)
+            .push(Button::new(es, Text::new("Emergency landing")).padding(15).on_press(StepMessage::EmergencyLand))
+            .push(Button::new(cs, Text::new("Cut motors")).padding(15).on_press(StepMessage::CutMotors)));
+
+    if !(error.is_empty()) {
+        return container.push(Text::new(error));
+    }
+    container
 }
\ No newline at end of file
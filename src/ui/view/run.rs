@@ -1,10 +1,13 @@
-use iced::{Column, Text, Button, Align};
+use iced::{Column, Row, Text, Button, Align};
 use iced::button::State as ButtonState;
 
 use crate::ui::model::StepMessage;
 
-pub fn run<'a>(container: Column<'a, StepMessage>, (start_state, stop_state): (&'a mut ButtonState, &'a mut ButtonState)) -> Column<'a, StepMessage> {
-    container
+pub fn run<'a>(container: Column<'a, StepMessage>,
+               (start_state, stop_state, emergency_state, cutoff_state): (&'a mut ButtonState, &'a mut ButtonState, &'a mut ButtonState, &'a mut ButtonState),
+               battery: Option<u32>,
+               error: &str) -> Column<'a, StepMessage> {
+    let mut container = container
         .align_items(Align::Center)
         .push(Column::new().align_items(Align::Start).spacing(10)
             .push(Text::new("Great! The drone will follow you from now on. You can start it up!"))
@@ -13,4 +16,17 @@ pub fn run<'a>(container: Column<'a, StepMessage>, (start_state, stop_state): (&
             .push(Text::new("After you are done, stay clear of the landing zone, and push the Stop button!")))
         .push(Button::new(start_state, Text::new("Start")).padding(15).on_press(StepMessage::Start))
         .push(Button::new(stop_state, Text::new("Stop")).padding(15).on_press(StepMessage::Stop))
+        .push(Row::new()
+            .spacing(10)
+            .push(Button::new(emergency_state, Text::new("Emergency landing")).padding(15).on_press(StepMessage::EmergencyLand))
+            .push(Button::new(cutoff_state, Text::new("Cut motors")).padding(15).on_press(StepMessage::CutMotors)))
+        .push(Text::new(match battery {
+            Some(level) => format!("Battery: {}%", level),
+            None => String::from("Battery: unknown"),
+        }));
+
+    if !(error.is_empty()) {
+        container = container.push(Text::new(error));
+    }
+    container
 }
\ No newline at end of file
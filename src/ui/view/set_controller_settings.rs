@@ -3,11 +3,20 @@ use iced::text_input::State as TIS;
 use iced::button::State as ButtonState;
 use crate::ui::model::{StepMessage, ControllerSetting, WindSetting, PersonSetting};
 
+use crate::parrot::connection::{ParrotConnection, DEFAULT_ADDRESS, DEFAULT_VIDEO_PORT, DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT};
+use crate::parrot::battery::BatterySettings;
+use crate::parrot::altitude_hold::AltitudeSettings;
+use crate::utils::calibration::ControllerGains;
+use crate::parrot::camera::CameraModel;
+use crate::simulation::dynamics::DynamicsSettings;
+
 pub fn set_controller_settings<'a>(container: Column<'a, StepMessage>,
                                    si: &'a mut ButtonState,
-                                   (cs, ws, ps): (Option<ControllerSetting>, Option<WindSetting>, Option<PersonSetting>)
+                                   (cs, ws, ps): (Option<ControllerSetting>, Option<WindSetting>, Option<PersonSetting>),
+                                   (addrs, ports, urls, bws, bcs, ags, vss, fgs, cams, dys): (&String, &String, &String, &String, &String, &String, &String, &String, &String, &String),
+                                   (addri, porti, urli, bwi, bci, agi, vsi, fgi, cami, dyi): (&'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS)
 ) -> Column<'a, StepMessage> {
-    container
+    let mut container = container
         .align_items(Align::Center)
         .push(Column::new().align_items(Align::Start).spacing(10)
             .push(Text::new("Controller:"))
@@ -49,6 +58,85 @@ pub fn set_controller_settings<'a>(container: Column<'a, StepMessage>,
                     ))
                 },
             )))
-         */
+         */;
+
+    if cs == Some(ControllerSetting::ParrotController) {
+        let default_battery = BatterySettings::default();
+        container = container
+            .push(Column::new().align_items(Align::Start).spacing(10)
+                .push(Text::new("Drone address:"))
+                .push(TextInput::new(
+                    addri,
+                    DEFAULT_ADDRESS,
+                    addrs.as_str(),
+                    StepMessage::DroneAddress).padding(15))
+                .push(Text::new("Video port:"))
+                .push(TextInput::new(
+                    porti,
+                    &DEFAULT_VIDEO_PORT.to_string(),
+                    ports.as_str(),
+                    StepMessage::VideoPort).padding(15))
+                .push(Text::new("Video URL (empty to use the address and the port):"))
+                .push(TextInput::new(
+                    urli,
+                    &ParrotConnection::default().video_url(),
+                    urls.as_str(),
+                    StepMessage::VideoUrl).padding(15))
+                .push(Text::new("Battery warning level (%):"))
+                .push(TextInput::new(
+                    bwi,
+                    &default_battery.warning_level.to_string(),
+                    bws.as_str(),
+                    StepMessage::BatteryWarning).padding(15))
+                .push(Text::new("Battery level at which the drone lands (%):"))
+                .push(TextInput::new(
+                    bci,
+                    &default_battery.critical_level.to_string(),
+                    bcs.as_str(),
+                    StepMessage::BatteryCritical).padding(15))
+                .push(Text::new("Altitude hold gains (kp ki kd, 0 0 0 to turn it off):"))
+                .push(TextInput::new(
+                    agi,
+                    &AltitudeSettings::default().to_line(),
+                    ags.as_str(),
+                    StepMessage::AltitudeGains).padding(15))
+                .push(Text::new("Camera (Ground or Front, then the field of view and the angle from straight down in degrees):"))
+                .push(TextInput::new(
+                    cami,
+                    &CameraModel::default().to_line(),
+                    cams.as_str(),
+                    StepMessage::DroneCamera).padding(15)));
+    }
+
+    if cs == Some(ControllerSetting::VirtualController) {
+        container = container
+            .push(Column::new().align_items(Align::Start).spacing(10)
+                .push(Text::new("Simulated video size (width height):"))
+                .push(TextInput::new(
+                    vsi,
+                    &format!("{} {}", DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT),
+                    vss.as_str(),
+                    StepMessage::VideoSize).padding(15))
+                .push(Text::new("Simulated drone (mass drag max_acceleration tilt_time_constant yaw_time_constant, in steps and pixels):"))
+                .push(TextInput::new(
+                    dyi,
+                    &DynamicsSettings::default().to_line(),
+                    dys.as_str(),
+                    StepMessage::Dynamics).padding(15)));
+    }
+
+    if cs.is_some() {
+        let default_gains = if cs == Some(ControllerSetting::ParrotController) { ControllerGains::PARROT } else { ControllerGains::VIRTUAL };
+        container = container
+            .push(Column::new().align_items(Align::Start).spacing(10)
+                .push(Text::new("Follow gains (kv ka, \"parrot_hat_follow calibrate\" suggests them):"))
+                .push(TextInput::new(
+                    fgi,
+                    &default_gains.to_line(),
+                    fgs.as_str(),
+                    StepMessage::FollowGains).padding(15)));
+    }
+
+    container
         .push(Button::new(si, Text::new("Save")).padding(15).on_press(StepMessage::SaveController))
 }
\ No newline at end of file
@@ -1,15 +1,28 @@
-use iced::{Column, Text, Button, TextInput, Align};
+use iced::{Column, Text, Button, TextInput, Radio, Checkbox, Align};
 use iced::text_input::State as TIS;
 use iced::button::State as ButtonState;
 
-use crate::ui::model::StepMessage;
+use crate::ui::model::{StepMessage, FilterSetting};
 
 pub fn set_kalman_settings<'a>(container: Column<'a, StepMessage>,
-                 (s0s, sgs, vls): (&String, &String, &String),
-                 (s0i, sgi, vli, si): (&'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut ButtonState)) -> Column<'a, StepMessage> {
-    container
-        .align_items(Align::Center)
-        .push(Column::new().align_items(Align::Start).spacing(20)
+                 (s0s, sgs, vls, mcs, fts, gts, pcs, seeds, tms, tss, pns, mns): (&String, &String, &String, &String, &String, &String, &String, &String, &String, &String, &String, &String),
+                 (s0i, sgi, vli, mci, fti, gti, pci, seedi, tmi, tsi, pni, mni, si): (&'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut TIS, &'a mut ButtonState),
+                 fs: Option<FilterSetting>,
+                 warm_start: bool,
+                 error: &str) -> Column<'a, StepMessage> {
+    let mut settings = Column::new().align_items(Align::Start).spacing(20)
+                  .push(Text::new("Filter:"))
+                  .push(FilterSetting::all().iter().cloned().fold(
+                    Column::new().padding(10).spacing(20),
+                    |choices, setting| {
+                        choices.push(Radio::new(
+                            setting,
+                            setting,
+                            fs,
+                            StepMessage::SetFilter
+                        ))
+                    },
+                  ))
                   .push(Text::new("Base uncertainty:"))
                   .push(TextInput::new(
                     s0i,
@@ -27,6 +40,82 @@ pub fn set_kalman_settings<'a>(container: Column<'a, StepMessage>,
                     vli,
                     "0.9",
                     vls.as_str(),
-                    StepMessage::VLose).padding(15)))
+                    StepMessage::VLose).padding(15))
+                  .push(Text::new("Frames to follow the predicted position when the hat is not detected:"))
+                  .push(TextInput::new(
+                    mci,
+                    "30",
+                    mcs.as_str(),
+                    StepMessage::MaxCoast).padding(15))
+                  .push(Text::new("Nominal time between frames in seconds (0 to count frames only):"))
+                  .push(TextInput::new(
+                    fti,
+                    "0.033",
+                    fts.as_str(),
+                    StepMessage::FrameTime).padding(15))
+                  .push(Text::new("Outlier gate, squared Mahalanobis distance (0 to accept every detection):"))
+                  .push(TextInput::new(
+                    gti,
+                    "0.0",
+                    gts.as_str(),
+                    StepMessage::GateThreshold).padding(15))
+                  .push(Text::new("Process noise (Q), 5 diagonal values or a full 5x5 matrix:"))
+                  .push(TextInput::new(
+                    pni,
+                    "1.0 1.0 2.0 1.0 1.0",
+                    pns.as_str(),
+                    StepMessage::ProcessNoise).padding(15))
+                  .push(Text::new("Measurement noise (R), 3 diagonal values or a full 3x3 matrix:"))
+                  .push(TextInput::new(
+                    mni,
+                    "10.0 10.0 0.001",
+                    mns.as_str(),
+                    StepMessage::MeasurementNoise).padding(15));
+
+    if fs == Some(FilterSetting::KalmanFilter) {
+        settings = settings
+                  .push(Checkbox::new(
+                    warm_start,
+                    "Continue from the filter state saved when the last run was stopped",
+                    StepMessage::WarmStart));
+    }
+    if fs == Some(FilterSetting::ParticleFilter) {
+        settings = settings
+                  .push(Text::new("Number of particles:"))
+                  .push(TextInput::new(
+                    pci,
+                    "500",
+                    pcs.as_str(),
+                    StepMessage::ParticleCount).padding(15))
+                  .push(Text::new("Random seed:"))
+                  .push(TextInput::new(
+                    seedi,
+                    "42",
+                    seeds.as_str(),
+                    StepMessage::Seed).padding(15));
+    }
+    if fs == Some(FilterSetting::ImmFilter) {
+        settings = settings
+                  .push(Text::new("Probability of starting to move in a frame:"))
+                  .push(TextInput::new(
+                    tmi,
+                    "0.05",
+                    tms.as_str(),
+                    StepMessage::ToMoving).padding(15))
+                  .push(Text::new("Probability of stopping in a frame:"))
+                  .push(TextInput::new(
+                    tsi,
+                    "0.05",
+                    tss.as_str(),
+                    StepMessage::ToStationary).padding(15));
+    }
+
+    if !(error.is_empty()) {
+        settings = settings.push(Text::new(format!("Invalid settings: {}", error)));
+    }
+
+    container
+        .align_items(Align::Center)
+        .push(settings)
         .push(Button::new(si, Text::new("Save")).padding(15).on_press(StepMessage::SaveKalman))
-}
\ No newline at end of file
+}
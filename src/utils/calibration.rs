@@ -0,0 +1,209 @@
+use std::thread;
+
+use rust_drone_follow::traits::Controller;
+
+use crate::utils::file_readers::read_controller_file;
+use crate::parrot::safety::LANDING_TIME;
+
+/// Share of the settled velocity change after one time constant of a first order response.
+const TIME_CONSTANT_SHARE: f64 = 0.632;
+
+/// Gains with which the HatFollower turns the offset of the hat into movement commands,
+/// as read from the eighth line of config.controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControllerGains {
+    /// Speed command per pixel of offset
+    pub kv: f64,
+    /// Turning command per radian of angle difference
+    pub ka: f64,
+}
+
+impl ControllerGains {
+    /// Uncalibrated gains of the ParrotController, turning is turned off.
+    pub const PARROT: ControllerGains = ControllerGains { kv: 0.003, ka: 0.0 };
+    /// Uncalibrated gains of the VirtualController.
+    pub const VIRTUAL: ControllerGains = ControllerGains { kv: 0.003, ka: 0.01 };
+
+    /// Parses a line of the form "kv ka", missing or invalid values are replaced by the given defaults.
+    pub fn parse(line: &str, default: ControllerGains) -> ControllerGains {
+        let args: Vec<&str> = line.split_whitespace().collect::<Vec<&str>>();
+        let value = |i: usize, default: f64| match args.get(i).map(|a| a.parse::<f64>()) {
+            Some(Ok(v)) if v.is_finite() && v >= 0.0 => v,
+            _ => default
+        };
+
+        ControllerGains {
+            kv: value(0, default.kv),
+            ka: value(1, default.ka),
+        }
+    }
+
+    /// The line parse reads back.
+    pub fn to_line(&self) -> String {
+        format!("{} {}", self.kv, self.ka)
+    }
+
+    /// The gains suggested by the step responses of moving and turning,
+    /// the current gain is kept for a response that could not be evaluated.
+    pub fn from_step_responses(moving: &StepResponse, turning: &StepResponse, current: ControllerGains) -> ControllerGains {
+        ControllerGains {
+            kv: moving.suggest_gain().unwrap_or(current.kv),
+            ka: turning.suggest_gain().unwrap_or(current.ka),
+        }
+    }
+}
+
+/// Velocities measured around a step of a single movement command. The samples with a negative time
+/// were taken while hovering before the step, the unit of time only has to be the same for all of them.
+#[derive(Debug, Clone)]
+pub struct StepResponse {
+    command: f64,
+    samples: Vec<(f64, f64)>,
+}
+
+impl StepResponse {
+    pub fn new(command: f64) -> StepResponse {
+        StepResponse {
+            command,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, time: f64, velocity: f64) {
+        self.samples.push((time, velocity));
+    }
+
+    /// The velocity while hovering (e.g. drift from the wind), the step response is measured relative to it.
+    fn baseline(&self) -> f64 {
+        let hover: Vec<f64> = self.samples.iter().filter(|(t, _)| *t < 0.0).map(|(_, v)| *v).collect();
+        if hover.is_empty() {
+            0.0
+        } else {
+            hover.iter().sum::<f64>() / hover.len() as f64
+        }
+    }
+
+    fn step(&self) -> Vec<(f64, f64)> {
+        self.samples.iter().filter(|(t, _)| *t >= 0.0).cloned().collect()
+    }
+
+    /// The settled change of the velocity per unit of command, taken from the last third of the step.
+    pub fn get_gain(&self) -> Option<f64> {
+        let step = self.step();
+        if step.len() < 3 || self.command == 0.0 {
+            return None;
+        }
+        let settled = &step[step.len() * 2 / 3..];
+        let change = settled.iter().map(|(_, v)| *v).sum::<f64>() / settled.len() as f64 - self.baseline();
+        let gain = change / self.command;
+        if gain.is_finite() && gain.abs() > 1e-9 {
+            Some(gain)
+        } else {
+            None
+        }
+    }
+
+    /// The time in which the velocity makes 63% of its settled change after the step.
+    pub fn get_time_constant(&self) -> Option<f64> {
+        let settled_change = self.get_gain()? * self.command;
+        let baseline = self.baseline();
+        let (mut last_time, mut last_share) = (0.0, 0.0);
+        for (time, velocity) in self.step() {
+            if time <= 0.0 {
+                continue;
+            }
+            let share = (velocity - baseline) / settled_change;
+            if share >= TIME_CONSTANT_SHARE {
+                return Some(last_time + (TIME_CONSTANT_SHARE - last_share) / (share - last_share) * (time - last_time));
+            }
+            last_time = time;
+            last_share = share;
+        }
+        None
+    }
+
+    /// The offset of the hat is the integral of the velocity, which follows the command with the lag
+    /// of the time constant. With a proportional command this loop is critically damped at 1 / (4 * gain * time constant),
+    /// the fastest gain that doesn't overshoot.
+    pub fn suggest_gain(&self) -> Option<f64> {
+        let gain = self.get_gain()?.abs();
+        let time_constant = self.get_time_constant()?;
+        Some(1.0 / (4.0 * gain * time_constant))
+    }
+}
+
+/// Measures the step responses of the controller set in config.controller and prints the suggested gains:
+/// the ParrotController takes off and flies the steps, the VirtualController simulates them.
+pub fn run_calibration(filename: &str) {
+    let (p_c_opt, v_c_opt) = match read_controller_file(filename) {
+        Ok(controllers) => controllers,
+        Err(e) => {
+            println!("Could not start the controller: {}", e);
+            return;
+        }
+    };
+
+    let (responses, current) = match (p_c_opt, v_c_opt) {
+        (Some(mut controller), _) => {
+            println!("Flying the calibration, keep at least 2 m of free space around the drone!");
+            let responses = controller.measure_step_responses();
+            controller.land();
+            // The drone is given time to land before the connection is closed
+            thread::sleep(LANDING_TIME);
+            (responses, controller.get_gains())
+        }
+        (None, Some(mut controller)) => (Ok(controller.measure_step_responses(100)), controller.get_gains()),
+        (None, None) => return,
+    };
+
+    match responses {
+        Ok((moving, turning)) => {
+            print_response("Moving", &moving);
+            print_response("Turning", &turning);
+            let suggested = ControllerGains::from_step_responses(&moving, &turning, current);
+            println!("Current gains (kv ka): {}", current.to_line());
+            println!("Suggested gains (kv ka): {}", suggested.to_line());
+        }
+        Err(e) => println!("The calibration failed: {}", e),
+    }
+}
+
+fn print_response(name: &str, response: &StepResponse) {
+    match (response.get_gain(), response.get_time_constant()) {
+        (Some(gain), Some(time_constant)) => println!("{}: {} per command, time constant {}", name, gain, time_constant),
+        _ => println!("{}: the response could not be measured, the current gain is kept", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::virtual_controller::VirtualController;
+    use crate::simulation::movetactics::stand_still::StandStill;
+    use crate::simulation::windtactics::no_wind::NoWind;
+
+    /// Checks the step response evaluation on an exact first order response (gain 5, time constant 4).
+    #[test]
+    fn step_response_test() {
+        let mut response = StepResponse::new(0.2);
+        for t in -20..60 {
+            let velocity = if t < 0 { 0.3 } else { 0.3 + 5.0 * 0.2 * (1.0 - (-t as f64 / 4.0).exp()) };
+            response.add_sample(t as f64, velocity);
+        }
+        let gain = response.get_gain().expect("The gain was not measured!");
+        let time_constant = response.get_time_constant().expect("The time constant was not measured!");
+        assert!((gain - 5.0).abs() < 0.1, "The gain is wrong: {}", gain);
+        assert!((time_constant - 4.0).abs() < 0.2, "The time constant is wrong: {}", time_constant);
+        assert!(StepResponse::new(0.2).suggest_gain().is_none(), "A gain was suggested without samples!");
+    }
+
+    /// Calibrates the VirtualController without wind and checks that the suggested gains are usable.
+    #[test]
+    fn virtual_calibration_test() {
+        let mut controller = VirtualController::new(20.0, 1, 0.0, StandStill::new(), NoWind::new(), false);
+        let (moving, turning) = controller.measure_step_responses(50);
+        let suggested = ControllerGains::from_step_responses(&moving, &turning, ControllerGains::VIRTUAL);
+        assert!(suggested.kv > 0.0 && suggested.kv.is_finite(), "The suggested kv is unusable: {}", suggested.kv);
+        assert!(suggested.ka > 0.0 && suggested.ka.is_finite(), "The suggested ka is unusable: {}", suggested.ka);
+    }
+}
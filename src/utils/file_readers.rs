@@ -1,8 +1,17 @@
 use rust_drone_follow::HatFollowerSettings;
 use std::fs;
 use std::time::{SystemTime, UNIX_EPOCH};
+use crate::kalman_filter::KalmanSettings;
+use crate::ui::model::FilterSetting;
 use crate::parrot::parrot_controller::ParrotController;
+use crate::parrot::connection::{ParrotConnection, DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT};
+use crate::parrot::parrot_error::ParrotError;
+use crate::parrot::battery::BatterySettings;
+use crate::parrot::altitude_hold::AltitudeSettings;
+use crate::utils::calibration::ControllerGains;
+use crate::parrot::camera::CameraModel;
 use crate::simulation::virtual_controller::VirtualController;
+use crate::simulation::dynamics::DynamicsSettings;
 use crate::simulation::movetactics::move_squares::MoveSquares;
 use crate::simulation::windtactics::periodic_wind::PeriodicWind;
 use crate::simulation::windtactics::random_wind::RandomWind;
@@ -38,42 +47,205 @@ pub fn read_follow_file(filename: &str) -> HatFollowerSettings {
     settings
 }
 
-pub fn read_kalman_file(filename: &str) -> (f64, f64, f64) {
+pub fn read_kalman_file(filename: &str) -> (FilterSetting, KalmanSettings) {
     let kalman_content = fs::read_to_string(filename)
         .expect("Something went wrong reading config.kalman the file");
-    let kalman_args: Vec<&str> = kalman_content.split(' ').collect::<Vec<&str>>();
+
+    (parse_filter_setting(kalman_content.as_str()), parse_kalman_settings(kalman_content.as_str()))
+}
+
+/// The fourth line of config.kalman chooses the filter (followed by its own parameters),
+/// the KalmanFilter is used if it is missing.
+pub fn parse_filter_setting(kalman_content: &str) -> FilterSetting {
+    let filter_line = kalman_content.split('\n').nth(3).unwrap_or("");
+    match filter_line.split_whitespace().next() {
+        Some("CtrvFilter") => FilterSetting::CtrvFilter,
+        Some("ParticleFilter") => FilterSetting::ParticleFilter,
+        Some("ImmFilter") => FilterSetting::ImmFilter,
+        _ => FilterSetting::KalmanFilter,
+    }
+}
+
+/// Parses the content of a config.kalman file:
+/// the first line holds sigma0, sigma_gain, est_v_loss, max_coast_frames, frame_time, gate_threshold and warm_start,
+/// the optional second and third lines hold the values of the Q and R matrices,
+/// the optional fourth line holds the chosen filter and its parameters.
+pub fn parse_kalman_settings(kalman_content: &str) -> KalmanSettings {
+    let default = KalmanSettings::default();
+    let kalman_lines: Vec<&str> = kalman_content.split('\n').collect::<Vec<&str>>();
+    let kalman_args: Vec<&str> = kalman_lines[0].split(' ').collect::<Vec<&str>>();
 
     let sigma0 = match kalman_args[0].trim().parse::<f64>() {
         Ok(mc) => mc,
-        _ => 1.0
+        _ => default.sigma0
     };
-    let sigma_gain = match kalman_args[1].trim().parse::<f64>() {
-        Ok(mc) => mc,
-        _ => 1.1
+    let sigma_gain = match kalman_args.get(1).map(|a| a.trim().parse::<f64>()) {
+        Some(Ok(mc)) => mc,
+        _ => default.sigma_gain
     };
-    let est_v_loss = match kalman_args[2].trim().parse::<f64>() {
-        Ok(mc) => mc,
-        _ => 1.0
+    let est_v_loss = match kalman_args.get(2).map(|a| a.trim().parse::<f64>()) {
+        Some(Ok(mc)) => mc,
+        _ => default.est_v_loss
+    };
+    let max_coast_frames = match kalman_args.get(3).map(|a| a.trim().parse::<usize>()) {
+        Some(Ok(mc)) => mc,
+        _ => default.max_coast_frames
+    };
+    let frame_time = match kalman_args.get(4).map(|a| a.trim().parse::<f64>()) {
+        Some(Ok(mc)) => mc,
+        _ => default.frame_time
     };
+    let gate_threshold = match kalman_args.get(5).map(|a| a.trim().parse::<f64>()) {
+        Some(Ok(mc)) => mc,
+        _ => default.gate_threshold
+    };
+    let warm_start = match kalman_args.get(6).map(|a| a.trim().parse::<bool>()) {
+        Some(Ok(ws)) => ws,
+        _ => default.warm_start
+    };
+    let q = match kalman_lines.get(1) {
+        Some(line) if !line.trim().is_empty() => parse_values(line),
+        _ => default.q
+    };
+    let r = match kalman_lines.get(2) {
+        Some(line) if !line.trim().is_empty() => parse_values(line),
+        _ => default.r
+    };
+    let filter_args: Vec<&str> = kalman_lines.get(3).unwrap_or(&"").split_whitespace().collect::<Vec<&str>>();
+    let (particle_count, seed) = match filter_args.first() {
+        Some(&"ParticleFilter") => (
+            match filter_args.get(1).map(|a| a.parse::<usize>()) {
+                Some(Ok(pc)) => pc,
+                _ => default.particle_count
+            },
+            match filter_args.get(2).map(|a| a.parse::<u64>()) {
+                Some(Ok(s)) => s,
+                _ => default.seed
+            }
+        ),
+        _ => (default.particle_count, default.seed)
+    };
+    let (imm_to_moving, imm_to_stationary) = match filter_args.first() {
+        Some(&"ImmFilter") => (
+            match filter_args.get(1).map(|a| a.parse::<f64>()) {
+                Some(Ok(p)) => p,
+                _ => default.imm_to_moving
+            },
+            match filter_args.get(2).map(|a| a.parse::<f64>()) {
+                Some(Ok(p)) => p,
+                _ => default.imm_to_stationary
+            }
+        ),
+        _ => (default.imm_to_moving, default.imm_to_stationary)
+    };
+
+    KalmanSettings {
+        sigma0,
+        sigma_gain,
+        est_v_loss,
+        q,
+        r,
+        max_coast_frames,
+        frame_time,
+        gate_threshold,
+        particle_count,
+        seed,
+        imm_to_moving,
+        imm_to_stationary,
+        warm_start,
+    }
+}
 
-    (sigma0, sigma_gain, est_v_loss)
+/// Parses a whitespace separated list of numbers, values that can't be parsed become NaN,
+/// so that they are reported when the settings are checked.
+pub fn parse_values(line: &str) -> Vec<f64> {
+    line.split_whitespace()
+        .map(|v| v.parse::<f64>().unwrap_or(std::f64::NAN))
+        .collect()
 }
 
-pub fn read_controller_file(filename: &str) -> (Option<ParrotController>, Option<VirtualController<MoveSquares, RandomWind>>) {
+pub fn read_controller_file(filename: &str) -> Result<(Option<ParrotController>, Option<VirtualController<MoveSquares, RandomWind>>), ParrotError> {
     let kalman_content = fs::read_to_string(filename)
-        .expect("Something went wrong reading controller.kalman the file");
+        .map_err(|e| ParrotError::ConfigUnreadable(format!("{}: {}", filename, e)))?;
     let kalman_args: Vec<&str> = kalman_content.split('\n').collect::<Vec<&str>>();
 
     match kalman_args[0] {
         "ParrotController" => {
-            (Some(ParrotController::new(300, true)), None)
+            let connection = ParrotConnection::parse(kalman_args.get(3).unwrap_or(&""));
+            let battery = BatterySettings::parse(kalman_args.get(4).unwrap_or(&""));
+            let altitude = AltitudeSettings::parse(kalman_args.get(5).unwrap_or(&""));
+            let gains = ControllerGains::parse(kalman_args.get(7).unwrap_or(&""), ControllerGains::PARROT);
+            let camera = CameraModel::parse(kalman_args.get(8).unwrap_or(&""));
+            Ok((Some(ParrotController::new(300, true, &connection, battery, altitude)?
+                .with_gains(gains)
+                .with_camera(camera)), None))
         }
         _ => {
 
-            (None, Some(VirtualController::new(20.0, 1, 0.01,
+            let (width, height) = parse_video_size(kalman_args.get(6).unwrap_or(&""));
+            let gains = ControllerGains::parse(kalman_args.get(7).unwrap_or(&""), ControllerGains::VIRTUAL);
+            let dynamics = DynamicsSettings::parse(kalman_args.get(9).unwrap_or(&""));
+            Ok((None, Some(VirtualController::new(20.0, 1, 0.01,
                 MoveSquares::new(0.7, 500),
                 RandomWind::new_polar(3.0, 150, 2000), false,
-            )))
+            ).with_video_size(width, height).with_gains(gains).with_dynamics(dynamics))))
         }
     }
 }
+
+/// Reads the connection of the drone from the fourth line of config.controller,
+/// the factory defaults are used if the file or the line is missing.
+pub fn read_connection(filename: &str) -> ParrotConnection {
+    match fs::read_to_string(filename) {
+        Ok(content) => ParrotConnection::parse(content.split('\n').nth(3).unwrap_or("")),
+        Err(_) => ParrotConnection::default(),
+    }
+}
+
+/// Reads the battery levels from the fifth line of config.controller,
+/// the defaults are used if the file or the line is missing.
+pub fn read_battery_settings(filename: &str) -> BatterySettings {
+    match fs::read_to_string(filename) {
+        Ok(content) => BatterySettings::parse(content.split('\n').nth(4).unwrap_or("")),
+        Err(_) => BatterySettings::default(),
+    }
+}
+
+/// Reads the gains of the altitude hold from the sixth line of config.controller,
+/// the defaults are used if the file or the line is missing.
+pub fn read_altitude_settings(filename: &str) -> AltitudeSettings {
+    match fs::read_to_string(filename) {
+        Ok(content) => AltitudeSettings::parse(content.split('\n').nth(5).unwrap_or("")),
+        Err(_) => AltitudeSettings::default(),
+    }
+}
+
+/// Reads the gains of the ParrotController from the eighth line of config.controller,
+/// the uncalibrated ones are used if the file or the line is missing.
+pub fn read_parrot_gains(filename: &str) -> ControllerGains {
+    match fs::read_to_string(filename) {
+        Ok(content) => ControllerGains::parse(content.split('\n').nth(7).unwrap_or(""), ControllerGains::PARROT),
+        Err(_) => ControllerGains::PARROT,
+    }
+}
+
+/// Reads the camera of the drone from the ninth line of config.controller,
+/// the ground camera is used if the file or the line is missing.
+pub fn read_camera_model(filename: &str) -> CameraModel {
+    match fs::read_to_string(filename) {
+        Ok(content) => CameraModel::parse(content.split('\n').nth(8).unwrap_or("")),
+        Err(_) => CameraModel::default(),
+    }
+}
+
+/// Parses the size of the simulated video ("width height") from the seventh line of config.controller,
+/// the AR.Drone's 640x360 is used if it is missing or invalid.
+pub fn parse_video_size(line: &str) -> (usize, usize) {
+    let args: Vec<usize> = line.split_whitespace()
+        .filter_map(|a| a.parse::<usize>().ok())
+        .collect();
+    match (args.first(), args.get(1)) {
+        (Some(&width), Some(&height)) if width > 0 && height > 0 => (width, height),
+        _ => (DEFAULT_VIDEO_WIDTH, DEFAULT_VIDEO_HEIGHT),
+    }
+}
@@ -0,0 +1,147 @@
+use rust_drone_follow::traits::Filter;
+use rust_drone_follow::models::GeometricPoint;
+use rust_drone_follow::utils::MarkerDrawer;
+use rust_drone_follow::utils::opencv_custom::get_blue;
+
+use opencv::core::Scalar;
+
+use crate::parrot::camera::CameraModel;
+use crate::parrot::altitude_hold::AltitudeLevel;
+
+/// Length of the direction (in pixels) that is projected to the ground to get the angle of the hat.
+const ANGLE_PROBE: f64 = 10.0;
+
+/// Wraps a Filter and projects the measurements of the chosen camera onto the ground before they are filtered.
+/// The HatFollower assumes a camera looking straight down, where a pixel of offset is the same distance
+/// everywhere in the image. The measurements are handed over in the pixels of the ground camera at the
+/// height of the drone, relative to the point where the hat is kept (see CameraModel::aim_distance), so the
+/// calibrated gains hold for every camera. The estimates stay in these pixels, the markers are projected back
+/// onto the image of the chosen camera.
+/// The measurements are expected in pixels from the center of the image, the y axis pointing to the top of it.
+/// The projection uses the altitude read from the navdata if it is given, the flight height until it is known.
+pub struct GroundProjection<F: Filter> {
+    filter: F,
+    camera: CameraModel,
+    width: f64,
+    flight_height: f64,
+    altitude: Option<AltitudeLevel>,
+}
+
+impl<F: Filter> GroundProjection<F> {
+    pub fn new(filter: F, camera: CameraModel, width: usize, flight_height: i32) -> GroundProjection<F> {
+        GroundProjection {
+            filter,
+            camera,
+            width: width as f64,
+            flight_height: flight_height as f64,
+            altitude: None,
+        }
+    }
+
+    /// Projects from the altitude of the drone (see ParrotController::get_altitude_level) instead of the flight height.
+    pub fn with_altitude_level(mut self, altitude: AltitudeLevel) -> GroundProjection<F> {
+        self.altitude = Some(altitude);
+        self
+    }
+
+    /// The current height of the camera above the ground in centimeters.
+    fn height(&self) -> f64 {
+        match self.altitude.as_ref().and_then(|a| a.lock().ok().and_then(|a| *a)) {
+            Some(altitude) => altitude as f64,
+            None => self.flight_height,
+        }
+    }
+
+    /// The pixel of the ground camera seen at the given pixel of the chosen camera, None above the horizon.
+    fn project(&self, (u, v): (f64, f64), height: f64) -> Option<(f64, f64)> {
+        let (x, y) = self.camera.pixel_to_ground((u, v), self.width, height)?;
+        let scale = CameraModel::ground().pixels_per_cm(self.width, height);
+        Some((x * scale, (y - self.camera.aim_distance(height)) * scale))
+    }
+
+    /// The pixel of the chosen camera where the given pixel of the ground camera is seen, None behind the camera.
+    fn project_back(&self, (x, y): (f64, f64), height: f64) -> Option<GeometricPoint> {
+        let scale = CameraModel::ground().pixels_per_cm(self.width, height);
+        let ground = (x / scale, y / scale + self.camera.aim_distance(height));
+        let (u, v) = self.camera.ground_to_pixel(ground, self.width, height)?;
+        Some(GeometricPoint::new(u.round() as i32, v.round() as i32))
+    }
+
+    /// The pixels of the chosen camera are the ones of the ground camera, nothing has to be projected.
+    fn is_ground_camera(&self) -> bool {
+        self.camera.mounting_angle == 0.0 && self.camera.horizontal_fov == CameraModel::ground().horizontal_fov
+    }
+}
+
+impl<F: Filter> Filter for GroundProjection<F> {
+    fn update_estimation(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, cert: f64) {
+        let (u, v) = match &point {
+            Some(p) => (p.x as f64, p.y as f64),
+            None => {
+                self.filter.update_estimation(None, angle, cert);
+                return;
+            }
+        };
+        let height = self.height();
+        let projected = match self.project((u, v), height) {
+            Some(p) => p,
+            // A hat above the horizon can't be on the ground, it is taken as a false detection
+            None => {
+                self.filter.update_estimation(None, None, cert);
+                return;
+            }
+        };
+        // The direction of the hat is distorted too, it is projected through a point in front of it
+        let projected_angle = angle.and_then(|a| {
+            let (x, y) = self.project((u + ANGLE_PROBE * a.cos(), v + ANGLE_PROBE * a.sin()), height)?;
+            Some((y - projected.1).atan2(x - projected.0))
+        });
+
+        self.filter.update_estimation(
+            Some(GeometricPoint::new(projected.0.round() as i32, projected.1.round() as i32)),
+            projected_angle,
+            cert
+        );
+    }
+
+    fn get_estimated_position(&self) -> Option<GeometricPoint> {
+        self.filter.get_estimated_position()
+    }
+
+    fn get_estimated_angle(&self) -> f64 {
+        self.filter.get_estimated_angle()
+    }
+
+    fn get_estimated_vx(&self) -> f64 {
+        self.filter.get_estimated_vx()
+    }
+
+    fn get_estimated_vy(&self) -> f64 {
+        self.filter.get_estimated_vy()
+    }
+
+    fn get_estimation_certainty(&self) -> f64 {
+        self.filter.get_estimation_certainty()
+    }
+
+    fn draw_on_image(&self, m_d: &mut MarkerDrawer) {
+        if self.is_ground_camera() {
+            self.filter.draw_on_image(m_d);
+            return;
+        }
+        // The markers of the wrapped filter would be drawn in the pixels of the ground camera,
+        // only the estimate and its direction are projected back onto the image
+        let p = match self.filter.get_estimated_position() {
+            Some(p) => p,
+            None => return,
+        };
+        let (x, y) = (p.x as f64, p.y as f64);
+        let (angle, height) = (self.filter.get_estimated_angle(), self.height());
+        if let Some(point) = self.project_back((x, y), height) {
+            m_d.point(&point, get_blue());
+            if let Some(other_point) = self.project_back((x + ANGLE_PROBE * angle.cos(), y + ANGLE_PROBE * angle.sin()), height) {
+                m_d.line(&point, &other_point, Scalar::new(255.0, 255.0, 255.0, 255.0));
+            }
+        }
+    }
+}
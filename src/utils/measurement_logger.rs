@@ -0,0 +1,121 @@
+use std::time::Instant;
+
+use rust_drone_follow::traits::Filter;
+use rust_drone_follow::models::GeometricPoint;
+use rust_drone_follow::utils::{MarkerDrawer, TextExporter};
+
+use crate::kalman_filter::{KalmanFilter, FrameTimestamp};
+use crate::ctrv_filter::CtrvFilter;
+use crate::particle_filter::ParticleFilter;
+use crate::imm_filter::ImmFilter;
+
+/// The inner state of a filter that is saved next to the measurements, to see afterwards how it behaved.
+pub trait FilterDiagnostics {
+    /// The extra columns of the row, after the update with the measurement.
+    fn diagnostics(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl FilterDiagnostics for KalmanFilter {
+    fn diagnostics(&self) -> Vec<String> {
+        vec![format!("{}", self.get_rejected_count())]
+    }
+}
+
+impl FilterDiagnostics for CtrvFilter {
+    fn diagnostics(&self) -> Vec<String> {
+        vec![format!("{}", self.get_rejected_count())]
+    }
+}
+
+impl FilterDiagnostics for ParticleFilter {}
+
+impl FilterDiagnostics for ImmFilter {
+    fn diagnostics(&self) -> Vec<String> {
+        let (stationary, moving) = self.get_model_probabilities();
+        vec![format!("{}", stationary), format!("{}", moving)]
+    }
+}
+
+/// Wraps a Filter and saves every measurement it receives (with the timestamp of its frame in seconds,
+/// or the time since the start if there are no timestamps) to a file, so that the session can be smoothed offline.
+/// Every row is: time, x, y, angle (missing values are written as a "-"), followed by the diagnostics
+/// of the filter: the number of rejected measurements so far for the KalmanFilter and the CtrvFilter,
+/// the probabilities of the stationary and the moving model for the ImmFilter.
+pub struct MeasurementLogger<F: Filter + FilterDiagnostics> {
+    filter: F,
+    te: TextExporter,
+    filename: String,
+    start: Instant,
+    timestamps: Option<FrameTimestamp>,
+}
+
+impl<F: Filter + FilterDiagnostics> MeasurementLogger<F> {
+    pub fn new(filter: F, filename: String) -> MeasurementLogger<F> {
+        MeasurementLogger {
+            filter,
+            te: TextExporter::new(),
+            filename,
+            start: Instant::now(),
+            timestamps: None,
+        }
+    }
+
+    /// Logs the timestamps of the frames, the same ones the filter steps on, see FrameTimestamp.
+    pub fn with_frame_timestamps(mut self, timestamps: FrameTimestamp) -> MeasurementLogger<F> {
+        self.timestamps = Some(timestamps);
+        self
+    }
+}
+
+impl<F: Filter + FilterDiagnostics> Filter for MeasurementLogger<F> {
+    fn update_estimation(&mut self, point: Option<GeometricPoint>, angle: Option<f64>, cert: f64) {
+        let time = match self.timestamps.as_ref().and_then(|t| t.lock().ok().and_then(|t| *t)) {
+            Some(timestamp) => timestamp,
+            None => self.start.elapsed().as_secs_f64(),
+        };
+        let (x, y) = match &point {
+            Some(p) => (format!("{}", p.x), format!("{}", p.y)),
+            None => (String::from("-"), String::from("-")),
+        };
+        let a = match angle {
+            Some(a) => format!("{}", a),
+            None => String::from("-"),
+        };
+
+        self.filter.update_estimation(point, angle, cert);
+
+        let mut row = format!("{}, {}, {}, {}", time, x, y, a);
+        for value in self.filter.diagnostics() {
+            row.push_str(", ");
+            row.push_str(&value);
+        }
+        row.push('\n');
+        self.te.save_row(self.filename.as_str(), row);
+    }
+
+    fn get_estimated_position(&self) -> Option<GeometricPoint> {
+        self.filter.get_estimated_position()
+    }
+
+    fn get_estimated_angle(&self) -> f64 {
+        self.filter.get_estimated_angle()
+    }
+
+    fn get_estimated_vx(&self) -> f64 {
+        self.filter.get_estimated_vx()
+    }
+
+    fn get_estimated_vy(&self) -> f64 {
+        self.filter.get_estimated_vy()
+    }
+
+    fn get_estimation_certainty(&self) -> f64 {
+        self.filter.get_estimation_certainty()
+    }
+
+    fn draw_on_image(&self, m_d: &mut MarkerDrawer) {
+        self.filter.draw_on_image(m_d);
+    }
+}
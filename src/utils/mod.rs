@@ -2,3 +2,7 @@ pub mod picture_recorder;
 pub mod picture_funcs;
 pub mod file_readers;
 pub mod testers;
+pub mod measurement_logger;
+pub mod smoother;
+pub mod calibration;
+pub mod ground_projection;
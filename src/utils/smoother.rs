@@ -0,0 +1,260 @@
+use std::{fmt, fs, io};
+
+use rulinalg::vector::Vector;
+use rulinalg::matrix::{Matrix, BaseMatrix};
+
+use linearkalman::{KalmanFilter as KF, predict_step, update_step};
+use linearkalman::KalmanState as KS;
+
+use crate::kalman_filter::{KalmanSettings, KalmanError, KalmanFilter, covariance_matrix, frames_between, normalize_angle};
+
+/// A single row of a measurement log: time of the frame in seconds, detected position and angle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub time: f64,
+    pub point: Option<(f64, f64)>,
+    pub angle: Option<f64>,
+}
+
+/// Reasons why a measurement log could not be smoothed.
+#[derive(Debug)]
+pub enum SmootherError {
+    /// The log could not be read or the result could not be written.
+    Io(io::Error),
+    Filter(KalmanError),
+}
+
+impl From<io::Error> for SmootherError {
+    fn from(e: io::Error) -> SmootherError {
+        SmootherError::Io(e)
+    }
+}
+
+impl From<KalmanError> for SmootherError {
+    fn from(e: KalmanError) -> SmootherError {
+        SmootherError::Filter(e)
+    }
+}
+
+impl fmt::Display for SmootherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmootherError::Io(e) => write!(f, "{}", e),
+            SmootherError::Filter(e) => write!(f, "invalid Kalman settings: {}", e),
+        }
+    }
+}
+
+/// Reads a measurement log written by the MeasurementLogger, the diagnostics of the filter are skipped.
+pub fn read_measurement_log(filename: &str) -> io::Result<Vec<Measurement>> {
+    let content = fs::read_to_string(filename)?;
+
+    Ok(content.split('\n')
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let values: Vec<Option<f64>> = line.split(',')
+                .map(|v| v.trim().parse::<f64>().ok())
+                .collect();
+            match values.as_slice() {
+                [Some(time), x, y, angle, ..] => Some(Measurement {
+                    time: *time,
+                    point: match (x, y) {
+                        (Some(x), Some(y)) => Some((*x, *y)),
+                        _ => None,
+                    },
+                    angle: *angle,
+                }),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// The result of the forward pass: the filtered states, and the predicted states and transitions
+/// between them, which the backward pass needs.
+struct ForwardPass {
+    filtered: Vec<KS>,
+    predicted: Vec<KS>,
+    transitions: Vec<Matrix<f64>>,
+}
+
+/// Runs a forward Kalman pass and a Rauch-Tung-Striebel backward pass over the measurements,
+/// with the same model as the live KalmanFilter. Returns the smoothed states (x, y, angle, vx, vy)
+/// from the first detection on, with their times.
+pub fn smooth_measurements(measurements: &[Measurement], settings: &KalmanSettings) -> Result<Vec<(f64, KS)>, KalmanError> {
+    let first = match measurements.iter().position(|m| m.point.is_some()) {
+        Some(i) => i,
+        None => return Ok(vec![]),
+    };
+    let measurements = &measurements[first..];
+    let ForwardPass { filtered, predicted, transitions } = forward_pass(measurements, settings)?;
+
+    // Backward pass
+    let n = filtered.len();
+    let mut smoothed: Vec<KS> = Vec::with_capacity(n);
+    smoothed.push(KS { x: filtered[n - 1].x.clone(), p: filtered[n - 1].p.clone() });
+    for k in (0..n - 1).rev() {
+        let next_smoothed = smoothed.last().unwrap();
+        let (pred, f) = (&predicted[k], &transitions[k]);
+        let current = &filtered[k];
+
+        let gain = match pred.p.clone().inverse() {
+            Ok(p_inv) => &current.p * f.transpose() * p_inv,
+            Err(_) => Matrix::zeros(5, 5),
+        };
+        let mut diff = &next_smoothed.x - &pred.x;
+        diff[2] = normalize_angle(diff[2]);
+
+        let mut x = &current.x + &gain * diff;
+        x[2] = normalize_angle(x[2]);
+        let p = &current.p + &gain * (&next_smoothed.p - &pred.p) * gain.transpose();
+        smoothed.push(KS { x, p });
+    }
+    smoothed.reverse();
+
+    Ok(measurements.iter().map(|m| m.time).zip(smoothed.into_iter()).collect())
+}
+
+/// The forward Kalman pass, the first measurement has to be a detection.
+fn forward_pass(measurements: &[Measurement], settings: &KalmanSettings) -> Result<ForwardPass, KalmanError> {
+    let q = covariance_matrix("Q", 5, &settings.q)?;
+    let r = covariance_matrix("R", 3, &settings.r)?;
+    let p0 = covariance_matrix("P0", 5, &[settings.sigma0; 5])?;
+
+    let h = Matrix::new(3, 5, vec![1.0, 0.0, 0.0, 0.0, 0.0,
+                                   0.0, 1.0, 0.0, 0.0, 0.0,
+                                   0.0, 0.0, 1.0, 0.0, 0.0]);
+    let mut filter = KF {
+        q: q.clone(),
+        r: r.clone(),
+        h: h.clone(),
+        f: Matrix::identity(5),
+        x0: Vector::zeros(5),
+        p0: Matrix::identity(5),
+    };
+    let mut position_filter = KF {
+        q: q.clone(),
+        r: r.select(&[0, 1], &[0, 1]),
+        h: h.select(&[0, 1], &[0, 1, 2, 3, 4]),
+        f: Matrix::identity(5),
+        x0: Vector::zeros(5),
+        p0: Matrix::identity(5),
+    };
+
+    let (x, y) = measurements[0].point.unwrap();
+    let mut state = KS {
+        x: vector![x, y, measurements[0].angle.unwrap_or(0.0), 0.0, 0.0],
+        p: p0,
+    };
+    let mut filtered = vec![KS { x: state.x.clone(), p: state.p.clone() }];
+    let mut predicted = vec![];
+    let mut transitions = vec![];
+
+    for pair in measurements.windows(2) {
+        let (previous, current) = (&pair[0], &pair[1]);
+        let dt = frames_between(settings.frame_time, previous.time, current.time);
+        let f = KalmanFilter::transition_matrix(settings.est_v_loss, dt);
+        filter.f = f.clone();
+        filter.q = &q * dt;
+        position_filter.f = f.clone();
+        position_filter.q = &q * dt;
+
+        let pred = predict_step(&filter, &state);
+        let mut next = match (current.point, current.angle) {
+            (Some((x, y)), Some(a)) => update_step(&filter, &pred, &vector![x, y, pred.x[2] + normalize_angle(a - pred.x[2])]),
+            (Some((x, y)), None) => update_step(&position_filter, &pred, &vector![x, y]),
+            _ => KS { x: pred.x.clone(), p: pred.p.clone() },
+        };
+        next.x[2] = normalize_angle(next.x[2]);
+
+        filtered.push(KS { x: next.x.clone(), p: next.p.clone() });
+        predicted.push(pred);
+        transitions.push(f);
+        state = next;
+    }
+
+    Ok(ForwardPass { filtered, predicted, transitions })
+}
+
+/// Smooths a measurement log and exports the positions and velocities to a CSV file.
+pub fn smooth_measurement_log(input: &str, output: &str, settings: &KalmanSettings) -> Result<usize, SmootherError> {
+    let smoothed = smooth_measurements(&read_measurement_log(input)?, settings)?;
+
+    let mut csv = String::from("time,x,y,angle,vx,vy\n");
+    for (time, state) in smoothed.iter() {
+        csv.push_str(format!("{},{},{},{},{},{}\n",
+                             time, state.x[0], state.x[1], state.x[2], state.x[3], state.x[4]).as_str());
+    }
+    fs::write(output, csv)?;
+    Ok(smoothed.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// The true position of the hat moving with a constant velocity in the given frame.
+    fn track(frame: usize) -> (f64, f64) {
+        (2.0 * frame as f64, -(frame as f64))
+    }
+
+    fn rms_error(states: &[&KS], frames: &[usize]) -> f64 {
+        let sum: f64 = states.iter().zip(frames.iter())
+            .map(|(state, frame)| {
+                let (x, y) = track(*frame);
+                (state.x[0] - x).powi(2) + (state.x[1] - y).powi(2)
+            })
+            .sum();
+        (sum / states.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn smoothing_test() {
+        let settings = KalmanSettings::default();
+        let mut rng = StdRng::seed_from_u64(42);
+
+        // Every 7th frame is dropped and every 10th frame the hat is not detected
+        let frames: Vec<usize> = (0..200).filter(|i| i % 7 != 3).collect();
+        let measurements: Vec<Measurement> = frames.iter()
+            .map(|&i| {
+                let (x, y) = track(i);
+                Measurement {
+                    time: i as f64 * settings.frame_time,
+                    point: if i % 10 == 5 { None } else { Some((x + rng.gen_range(-3.0, 3.0), y + rng.gen_range(-3.0, 3.0))) },
+                    angle: Some(0.0),
+                }
+            })
+            .collect();
+
+        let forward = forward_pass(&measurements, &settings).unwrap();
+        let smoothed = smooth_measurements(&measurements, &settings).unwrap();
+        assert_eq!(smoothed.len(), measurements.len());
+        assert_eq!(forward.filtered.len(), measurements.len());
+
+        // The first frames are skipped, where the velocity has not been found yet
+        let forward_error = rms_error(&forward.filtered.iter().skip(20).collect::<Vec<_>>(), &frames[20..]);
+        let smoothed_error = rms_error(&smoothed.iter().skip(20).map(|(_, s)| s).collect::<Vec<_>>(), &frames[20..]);
+        assert!(smoothed_error < forward_error, "smoothed: {}, forward: {}", smoothed_error, forward_error);
+
+        let (_, last) = smoothed.last().unwrap();
+        assert!((last.x[3] - 2.0).abs() < 0.5 && (last.x[4] + 1.0).abs() < 0.5, "velocity: {}, {}", last.x[3], last.x[4]);
+    }
+
+    #[test]
+    fn missing_log_test() {
+        let input = std::env::temp_dir().join("missing_measurements.txt");
+        let output = std::env::temp_dir().join("missing_measurements.csv");
+        match smooth_measurement_log(input.to_str().unwrap(), output.to_str().unwrap(), &KalmanSettings::default()) {
+            Err(SmootherError::Io(_)) => (),
+            other => panic!("expected an Io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn no_detection_test() {
+        let measurements = vec![Measurement { time: 0.0, point: None, angle: None }; 5];
+        assert!(smooth_measurements(&measurements, &KalmanSettings::default()).unwrap().is_empty());
+    }
+}
@@ -3,15 +3,20 @@ use std::{thread, io};
 use std::num::ParseIntError;
 
 use rust_drone_follow::HatFollower;
+use rust_drone_follow::traits::Controller;
 use rust_drone_follow::HatFollowerSettings;
 use rust_drone_follow::utils::hat_file_reader::read_file;
 use rust_drone_follow::detectors::naive_detector::NaiveDetector;
 use rust_drone_follow::controllers::mock_controller::MockController;
 
 use crate::parrot::parrot_controller::ParrotController;
+use crate::parrot::safety;
+use crate::utils::file_readers::{read_connection, read_battery_settings, read_altitude_settings, read_parrot_gains, read_camera_model};
 use crate::kalman_filter::KalmanFilter;
+use crate::parrot::connection::DEFAULT_ADDRESS;
+use crate::utils::ground_projection::GroundProjection;
 
-use parrot_ar_drone::NavDataValue;
+use crate::parrot::drone::{Drone, NavDataValue};
 
 pub fn run_follow_test() {
 
@@ -46,7 +51,7 @@ fn follow_test(filename: &str, settings: HatFollowerSettings) {
 
 pub fn drone_test() {
     println!("Start!");
-    let mut drone = parrot_ar_drone::Drone::new();
+    let mut drone = Drone::connect(DEFAULT_ADDRESS).unwrap();
 
     println!("Instantiating done!");
     drone.startup().unwrap();
@@ -72,11 +77,40 @@ pub fn run_noui_follow(filename: &str, settings: HatFollowerSettings) {
     let (sx, rx) = std::sync::mpsc::channel();
     let (_, hat) = read_file(filename);
 
+    let started = ParrotController::new(
+            220,
+            true,
+            &read_connection("config.controller"),
+            read_battery_settings("config.controller"),
+            read_altitude_settings("config.controller"))
+        .map(|controller| controller
+            .with_gains(read_parrot_gains("config.controller"))
+            .with_camera(read_camera_model("config.controller")))
+        .and_then(|mut controller| {
+            controller.try_init()?;
+            controller.try_takeoff()?;
+            Ok(controller)
+        });
+    let mut controller = match started {
+        Ok(controller) => controller,
+        Err(e) => {
+            println!("Could not start following: {}", e);
+            return;
+        }
+    };
+    controller.set_stop_sender(sx.clone());
+    let filter = GroundProjection::new(
+        KalmanFilter::new(1.0, 1.1, 1.0).with_frame_timestamps(controller.get_frame_timestamps()),
+        controller.get_camera(),
+        controller.get_video_width(),
+        controller.get_flight_height()
+    ).with_altitude_level(controller.get_altitude_level());
+
     let handle = thread::spawn(|| {
         let mut hf = HatFollower::new(
             NaiveDetector::new(hat),
-            ParrotController::new(220, true),
-            KalmanFilter::new(1.0, 1.1, 1.0),
+            controller,
+            filter,
             settings,
             Some(rx)
         );
@@ -84,11 +118,22 @@ pub fn run_noui_follow(filename: &str, settings: HatFollowerSettings) {
         hf.run();
     });
 
+    println!("Type 0 to stop following and land, 9 for an emergency landing, or 8 to cut the motors!");
     loop {
         let i = read_int();
         match i {
             Ok(0) | Err(_) => {
-                sx.send(0).unwrap();
+                // The follower may have stopped already (e.g. landed on low battery)
+                let _ = sx.send(0);
+                break;
+            }
+            Ok(9) => {
+                // The controller lands on its next frame and stops the follower
+                safety::request_emergency_landing();
+                break;
+            }
+            Ok(8) => {
+                safety::cut_motors();
                 break;
             }
             Ok(_) => {